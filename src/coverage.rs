@@ -0,0 +1,316 @@
+//! Gene coverage of a coordinate sorted bam, streamed per chromosome.
+
+use std::collections::VecDeque;
+
+use coitrees::{COITree, Interval, IntervalTree};
+use indexmap::IndexMap;
+use rust_htslib::bam;
+
+use crate::{add_coverage, calculate_coverage, oversized_contigs, reference_span, same_contig, SpanOps, DEFAULT_EXCLUDE_FLAGS, MAX_POSITION};
+
+
+/// Target region in bed coordinates, regions with the same name form one gene.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Region {
+    pub chrom: String,
+    pub start: u64,
+    pub end: u64,
+    pub name: String,
+}
+
+/// Coverage of one region of a gene.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AmpliconCoverage {
+    pub start: i64,
+    pub end: i64,
+    pub length: i64, // end - start + 1, same as perl version of seq2c
+    pub count: i64, // sum of read bases overlapping the region
+    pub mean_depth: f64,
+}
+
+/// Coverage of all regions of a gene on one chromosome.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GeneCoverage {
+    pub gene: String,
+    pub chrom: String,
+    pub start: i64,
+    pub end: i64,
+    pub length: i64,
+    pub count: i64,
+    pub mean_depth: f64,
+    pub amplicons: Vec<AmpliconCoverage>,
+}
+
+#[derive(Debug)]
+pub enum CoverageError {
+    Htslib(rust_htslib::errors::Error),
+    /// Reads of the chromosome were found after its genes were already yielded.
+    Unsorted(String),
+    /// Regions of the chromosome or its bam contig reach beyond `MAX_POSITION`.
+    TooLong(String),
+    /// A read has a reference id without a contig in the bam header, e.g. of a truncated or corrupt header.
+    UnknownContig(i32),
+}
+
+impl std::fmt::Display for CoverageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            CoverageError::Htslib(e) => write!(f, "error reading bam: {e}"),
+            CoverageError::Unsorted(chrom) => write!(f, "bam is not coordinate sorted, reads of {chrom} found after it was finished"),
+            CoverageError::TooLong(chrom) => write!(f, "{chrom} has positions beyond {MAX_POSITION}, longer contigs are not supported"),
+            CoverageError::UnknownContig(tid) => write!(f, "read has reference id {tid} which is not in the bam header"),
+        }
+    }
+}
+
+impl std::error::Error for CoverageError {}
+
+
+// Regions of one chromosome with their counts, tree metadata is the region index
+struct ChromTargets {
+    regions: Vec<Region>,
+    counts: Vec<i64>,
+    tree: COITree<usize, u32>,
+    finished: bool,
+}
+
+
+/// Counts coverage of bed regions with the same defaults as the seq2c-rs binary:
+/// unmapped, secondary, QC fail and supplementary reads are skipped and `M,D` operations are covered.
+///
+/// ```
+/// use rust_htslib::bam;
+/// use seq2c_rs::{CoverageCalculator, Region};
+///
+/// let sam = "@SQ\tSN:chr1\tLN:1000\n\
+///            r1\t0\tchr1\t101\t60\t50M\t*\t0\t0\t*\t*\n\
+///            r2\t0\tchr1\t151\t60\t50M\t*\t0\t0\t*\t*\n";
+/// let path = std::env::temp_dir().join("seq2c-rs-coverage-doctest.sam");
+/// std::fs::write(&path, sam).unwrap();
+///
+/// let region = |start, end| Region{ chrom: String::from("chr1"), start, end, name: String::from("GENE1") };
+/// let calculator = CoverageCalculator::new([region(100, 149), region(150, 199)]);
+/// for gene in calculator.coverage_iter(bam::Reader::from_path(&path).unwrap()) {
+///     let gene = gene.unwrap();
+///     assert_eq!((gene.gene.as_str(), gene.start, gene.end, gene.count), ("GENE1", 100, 199, 99));
+///     assert_eq!(gene.amplicons.len(), 2);
+/// }
+/// std::fs::remove_file(&path).unwrap();
+/// ```
+pub struct CoverageCalculator {
+    regions: IndexMap<String, Vec<Region>>,
+    span_ops: SpanOps,
+    exclude_flags: u16,
+    min_mapq: u8,
+}
+
+impl CoverageCalculator {
+    pub fn new(regions: impl IntoIterator<Item = Region>) -> CoverageCalculator {
+        let mut by_chrom = IndexMap::<String, Vec<Region>>::new();
+        for region in regions {
+            by_chrom.entry(region.chrom.clone()).or_default().push(region);
+        }
+        CoverageCalculator { regions: by_chrom, span_ops: SpanOps::default(), exclude_flags: DEFAULT_EXCLUDE_FLAGS, min_mapq: 0 }
+    }
+
+    pub fn span_ops(mut self, span_ops: SpanOps) -> CoverageCalculator {
+        self.span_ops = span_ops;
+        self
+    }
+
+    /// Reads with any of these flag bits are skipped, default is [`DEFAULT_EXCLUDE_FLAGS`].
+    pub fn exclude_flags(mut self, exclude_flags: u16) -> CoverageCalculator {
+        self.exclude_flags = exclude_flags;
+        self
+    }
+
+    pub fn min_mapq(mut self, min_mapq: u8) -> CoverageCalculator {
+        self.min_mapq = min_mapq;
+        self
+    }
+
+    /// Yields genes of every chromosome as soon as reads of the next chromosome start,
+    /// genes of chromosomes without reads follow at the end in bed order.
+    ///
+    /// The bam has to be coordinate sorted, reads of an already finished chromosome yield `CoverageError::Unsorted`.
+    /// Regions or bam contigs with targets beyond `MAX_POSITION` yield `CoverageError::TooLong` before any gene.
+    /// Reads with a reference id missing from the bam header yield `CoverageError::UnknownContig`.
+    pub fn coverage_iter<R: bam::Read>(&self, reader: R) -> CoverageIter<'_, R> {
+        let targets = self.regions.iter().map(|(chrom, regions)| {
+            let nodes: Vec<Interval<usize>> = regions.iter().enumerate()
+                .map(|(i, region)| Interval::new(region.start as i32, region.end as i32, i))
+                .collect();
+            (chrom.clone(), ChromTargets { regions: regions.clone(), counts: vec![0; regions.len()], tree: COITree::new(&nodes), finished: false })
+        }).collect::<IndexMap<_, _>>();
+        let header = reader.header();
+        let bam_chroms: Vec<String> = (0..header.target_count()).map(|tid| String::from_utf8_lossy(header.tid2name(tid)).to_string()).collect();
+        // Mitochondrial contigs also match across naming conventions if the exact name is missing
+        let tid_targets = bam_chroms.iter()
+            .map(|chrom| targets.get_index_of(chrom).or_else(|| {
+                targets.keys().position(|name| same_contig(name, chrom) && !bam_chroms.contains(name))
+            }))
+            .collect::<Vec<_>>();
+        let oversized = targets.iter()
+            .find(|(_, targets)| targets.regions.iter().any(|region| region.end > MAX_POSITION as u64))
+            .map(|(chrom, _)| chrom.clone())
+            .or_else(|| oversized_contigs(header).into_iter()
+                .find(|(chrom, _)| tid_targets[bam_chroms.iter().position(|name| name == chrom).unwrap()].is_some())
+                .map(|(chrom, _)| chrom));
+        CoverageIter { calculator: self, on_read: None, reader, record: bam::Record::new(), targets, tid_targets, current: None, pending: VecDeque::new(), done: false, oversized }
+    }
+
+    /// All genes at once, see `coverage_iter`.
+    pub fn coverage<R: bam::Read>(&self, reader: R) -> Result<Vec<GeneCoverage>, CoverageError> {
+        self.coverage_iter(reader).collect()
+    }
+}
+
+
+type ReadHook<'a> = Box<dyn FnMut(&bam::Record) + 'a>;
+
+pub struct CoverageIter<'a, R: bam::Read> {
+    calculator: &'a CoverageCalculator,
+    on_read: Option<ReadHook<'a>>,
+    reader: R,
+    record: bam::Record,
+    targets: IndexMap<String, ChromTargets>,
+    tid_targets: Vec<Option<usize>>, // targets index by bam tid
+    current: Option<usize>, // targets index of the chromosome being counted
+    pending: VecDeque<GeneCoverage>,
+    done: bool,
+    oversized: Option<String>, // chromosome that can't be indexed, yielded as the first item
+}
+
+impl<'a, R: bam::Read> CoverageIter<'a, R> {
+    /// Calls `hook` for every read that passed filters before it's counted, e.g. to collect
+    /// custom statistics in the same pass over the bam.
+    ///
+    /// The hook runs on the thread that calls `next`, reads on contigs without regions are passed too.
+    ///
+    /// ```
+    /// use rust_htslib::bam;
+    /// use seq2c_rs::{CoverageCalculator, Region};
+    ///
+    /// let sam = "@SQ\tSN:chr1\tLN:1000\n\
+    ///            r1\t0\tchr1\t101\t60\t50M\t*\t0\t0\t*\t*\n\
+    ///            r2\t16\tchr1\t151\t60\t50M\t*\t0\t0\t*\t*\n";
+    /// let path = std::env::temp_dir().join("seq2c-rs-on-read-doctest.sam");
+    /// std::fs::write(&path, sam).unwrap();
+    ///
+    /// let calculator = CoverageCalculator::new([Region{ chrom: String::from("chr1"), start: 100, end: 199, name: String::from("GENE1") }]);
+    /// let mut reverse_reads = 0;
+    /// let genes: Vec<_> = calculator.coverage_iter(bam::Reader::from_path(&path).unwrap())
+    ///     .on_read(|record| if record.is_reverse() { reverse_reads += 1 })
+    ///     .collect();
+    /// assert_eq!(genes.len(), 1);
+    /// assert_eq!(reverse_reads, 1);
+    /// std::fs::remove_file(&path).unwrap();
+    /// ```
+    pub fn on_read(mut self, hook: impl FnMut(&bam::Record) + 'a) -> CoverageIter<'a, R> {
+        self.on_read = Some(Box::new(hook));
+        self
+    }
+
+    // Moves genes of the chromosome into pending, regions are ordered by gene name and position
+    fn finish(&mut self, target: usize) {
+        let (chrom, targets) = self.targets.get_index_mut(target).unwrap();
+        targets.finished = true;
+        let mut order: Vec<usize> = (0..targets.regions.len()).collect();
+        order.sort_by(|&a, &b| {
+            let (a, b) = (&targets.regions[a], &targets.regions[b]);
+            (&a.name, a.start, a.end).cmp(&(&b.name, b.start, b.end))
+        });
+        for i in order {
+            let region = &targets.regions[i];
+            let length = (region.end - region.start + 1) as i64;
+            let count = targets.counts[i];
+            let amplicon = AmpliconCoverage {
+                start: region.start as i64,
+                end: region.end as i64,
+                length,
+                count,
+                mean_depth: count as f64 / length as f64,
+            };
+            match self.pending.back_mut() {
+                Some(gene) if gene.gene == region.name && gene.chrom == *chrom => {
+                    gene.start = gene.start.min(amplicon.start);
+                    gene.end = gene.end.max(amplicon.end);
+                    gene.length += length;
+                    gene.count = gene.count.saturating_add(count);
+                    gene.mean_depth = gene.count as f64 / gene.length as f64;
+                    gene.amplicons.push(amplicon);
+                },
+                _ => self.pending.push_back(GeneCoverage {
+                    gene: region.name.clone(),
+                    chrom: chrom.clone(),
+                    start: amplicon.start,
+                    end: amplicon.end,
+                    length,
+                    count,
+                    mean_depth: amplicon.mean_depth,
+                    amplicons: vec![amplicon],
+                }),
+            }
+        }
+    }
+}
+
+impl<R: bam::Read> Iterator for CoverageIter<'_, R> {
+    type Item = Result<GeneCoverage, CoverageError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(chrom) = self.oversized.take() {
+            self.done = true;
+            return Some(Err(CoverageError::TooLong(chrom)));
+        }
+        while self.pending.is_empty() && !self.done {
+            match self.reader.read(&mut self.record) {
+                Some(Ok(())) => (),
+                Some(Err(e)) => {
+                    self.done = true;
+                    return Some(Err(CoverageError::Htslib(e)));
+                },
+                None => {
+                    // Remaining chromosomes, including the ones without reads
+                    self.done = true;
+                    for target in 0..self.targets.len() {
+                        if !self.targets[target].finished {
+                            self.finish(target);
+                        }
+                    }
+                    break;
+                },
+            }
+            let (flags, tid, mapq) = (self.record.flags(), self.record.tid(), self.record.mapq());
+            if flags & self.calculator.exclude_flags != 0 || tid < 0 || mapq < self.calculator.min_mapq {
+                continue;
+            }
+            let Some(&target) = self.tid_targets.get(tid as usize) else {
+                self.done = true;
+                return Some(Err(CoverageError::UnknownContig(tid)));
+            };
+            if let Some(on_read) = self.on_read.as_mut() {
+                on_read(&self.record);
+            }
+            if target != self.current {
+                if let Some(current) = self.current {
+                    self.finish(current);
+                }
+                self.current = target;
+            }
+            let Some(target) = target else { continue };
+            let targets = &mut self.targets[target];
+            if targets.finished {
+                self.done = true;
+                return Some(Err(CoverageError::Unsorted(self.targets.get_index(target).unwrap().0.clone())));
+            }
+            let (start, end) = reference_span(&self.record.cigar(), self.record.pos(), self.calculator.span_ops);
+            let counts = &mut targets.counts;
+            targets.tree.query((start - 1) as i32, (end + 1) as i32, |node| {
+                let i = node.metadata;
+                counts[i] = add_coverage(counts[i], calculate_coverage(start..end, node.first as i64..node.last as i64), 1);
+            });
+        }
+        self.pending.pop_front().map(Ok)
+    }
+}
@@ -0,0 +1,680 @@
+//! Coordinate math shared by the seq2c-rs binary and a streaming gene coverage API.
+//!
+//! Reads are placed on the reference with 1-based inclusive spans, bed regions are compared
+//! against them as `[start, end]` with both ends inclusive, same as in perl version of seq2c.
+
+use std::str::FromStr;
+
+use rust_htslib::bam;
+use rust_htslib::bam::record::Cigar;
+
+mod coverage;
+pub use coverage::{AmpliconCoverage, CoverageCalculator, CoverageError, CoverageIter, GeneCoverage, Region};
+
+
+/// Number of bases shared by two end-inclusive intervals, `a.end` and `b.end` are part of the interval.
+///
+/// Result is 0 for intervals that only touch or are disjoint, it's never negative.
+///
+/// ```
+/// use seq2c_rs::calculate_coverage;
+///
+/// assert_eq!(calculate_coverage(101..150, 100..200), 50); // nested
+/// assert_eq!(calculate_coverage(190..240, 100..200), 11); // partial overlap
+/// assert_eq!(calculate_coverage(200..240, 100..200), 1);  // single shared base
+/// assert_eq!(calculate_coverage(201..240, 100..200), 0);  // adjacent
+/// assert_eq!(calculate_coverage(300..400, 100..200), 0);  // disjoint
+///
+/// // Properties over all pairs of small intervals
+/// for a_start in 0..12 {
+///     for a_end in a_start..12 {
+///         for b_start in 0..12 {
+///             for b_end in b_start..12 {
+///                 let overlap = calculate_coverage(a_start..a_end, b_start..b_end);
+///                 assert_eq!(overlap, calculate_coverage(b_start..b_end, a_start..a_end));
+///                 assert!(overlap >= 0);
+///                 assert!(overlap <= (a_end - a_start + 1).min(b_end - b_start + 1));
+///             }
+///         }
+///     }
+/// }
+/// ```
+pub fn calculate_coverage(a: std::ops::Range<i64>, b: std::ops::Range<i64>) -> i64 {
+    // Find the start and end of the intersection
+    let intersection_start = std::cmp::max(a.start, b.start);
+    let intersection_end = std::cmp::min(a.end, b.end);
+    
+    (intersection_end - intersection_start + 1).max(0)
+}
+
+
+/// Adds coverage of a read with weight to a region count, saturating at `i64::MAX` instead of overflowing.
+///
+/// A saturated count can't grow anymore, so `i64::MAX` marks it as unreliable.
+///
+/// ```
+/// use seq2c_rs::add_coverage;
+///
+/// assert_eq!(add_coverage(100, 50, 60), 3100);
+/// assert_eq!(add_coverage(i64::MAX - 10, 50, 1), i64::MAX);
+/// assert_eq!(add_coverage(0, i64::MAX / 2, 60), i64::MAX);
+/// ```
+pub fn add_coverage(count: i64, coverage: i64, weight: i64) -> i64 {
+    count.saturating_add(coverage.saturating_mul(weight))
+}
+
+
+/// Weighted coverage a region gets when the bases a read has on all regions are split among them by overlap.
+///
+/// `overlap` is the number of read bases on the region, `overlap_sum` the overlaps of all regions of the read summed up
+/// and `union` the read bases on any of them. Each region gets `overlap * union / overlap_sum` bases, so shares
+/// of a read sum up to its bases on target instead of crediting shared bases to every region. The result is
+/// multiplied by `weight` and rounded, callers scale weights to keep the fractions.
+///
+/// ```
+/// use seq2c_rs::overlap_share;
+///
+/// // 100 base read on two amplicons sharing 20 of its bases: overlaps 60 and 60, union 100
+/// assert_eq!(overlap_share(60, 100, 120, 1000), 50000);
+///
+/// // Three tiled amplicons with overlaps 50, 100 and 30 of a 150 base read, union 150
+/// let shares: Vec<i64> = [50, 100, 30].iter().map(|&overlap| overlap_share(overlap, 150, 180, 1000)).collect();
+/// assert_eq!(shares, vec![41667, 83333, 25000]);
+/// assert_eq!(shares.iter().sum::<i64>(), 150000);
+///
+/// // A single region gets all of its bases
+/// assert_eq!(overlap_share(70, 70, 70, 1), 70);
+/// ```
+pub fn overlap_share(overlap: i64, union: i64, overlap_sum: i64, weight: i64) -> i64 {
+    if overlap_sum <= 0 {
+        return 0;
+    }
+    let share = (overlap as i128 * union as i128 * weight as i128 * 2 + overlap_sum as i128) / (overlap_sum as i128 * 2);
+    share.min(i64::MAX as i128) as i64
+}
+
+
+/// Merges overlapping and touching `[start, end)` intervals, input is sorted in place.
+pub fn merge_intervals(intervals: &mut [(i64, i64)]) -> Vec<(i64, i64)> {
+    intervals.sort_unstable();
+    let mut merged: Vec<(i64, i64)> = Vec::new();
+    for &(start, end) in intervals.iter() {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    merged
+}
+
+
+/// Reference consuming CIGAR operations counted as covered by the read.
+///
+/// Parsed from a comma separated list of `M`, `=`, `X`, `D`, `N`, default is `M,D`
+/// which is the same as perl version of seq2c.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpanOps(u8);
+
+impl SpanOps {
+    const OPS: [char; 5] = ['M', '=', 'X', 'D', 'N']; // bit order of the mask
+    pub const DEL: u8 = 1 << 3;
+    pub const REF_SKIP: u8 = 1 << 4;
+
+    /// Copy with operation bit (`SpanOps::DEL`, `SpanOps::REF_SKIP`) enabled or disabled.
+    pub fn with(self, bit: u8, enabled: bool) -> SpanOps {
+        if enabled { SpanOps(self.0 | bit) } else { SpanOps(self.0 & !bit) }
+    }
+
+    pub fn contains(self, op: &Cigar) -> bool {
+        let bit = match op {
+            Cigar::Match(_) => 1,
+            Cigar::Equal(_) => 1 << 1,
+            Cigar::Diff(_) => 1 << 2,
+            Cigar::Del(_) => Self::DEL,
+            Cigar::RefSkip(_) => Self::REF_SKIP,
+            _ => 0,
+        };
+        self.0 & bit != 0
+    }
+}
+
+impl Default for SpanOps {
+    fn default() -> SpanOps {
+        SpanOps(1 | Self::DEL)
+    }
+}
+
+impl FromStr for SpanOps {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<SpanOps, String> {
+        let mut mask = 0;
+        for op in value.split(',') {
+            match SpanOps::OPS.iter().position(|c| c.to_string() == op.trim()) {
+                Some(i) => mask |= 1 << i,
+                None => return Err(format!("'{op}' is not a reference consuming CIGAR operation, expected any of M,=,X,D,N")),
+            }
+        }
+        Ok(SpanOps(mask))
+    }
+}
+
+
+/// 1-based inclusive `(start, end)` span of a read aligned at 0-based `pos`.
+///
+/// Span length is the sum of operations from `span_ops`, other operations don't extend it,
+/// e.g. with default `M,D` an intron (`N`) shortens the span instead of being skipped over.
+///
+/// ```
+/// use rust_htslib::bam::record::Cigar;
+/// use seq2c_rs::{reference_span, SpanOps};
+///
+/// let cigar = [Cigar::SoftClip(5), Cigar::Match(10), Cigar::Del(5), Cigar::Match(10), Cigar::RefSkip(2), Cigar::Match(10)];
+/// assert_eq!(reference_span(&cigar, 100, SpanOps::default()), (101, 135));
+/// assert_eq!(reference_span(&cigar, 100, "M".parse().unwrap()), (101, 130));
+/// assert_eq!(reference_span(&cigar, 100, "M,D,N".parse().unwrap()), (101, 137));
+/// assert_eq!(reference_span(&[Cigar::Ins(4)], 100, SpanOps::default()), (101, 100)); // empty span
+/// ```
+pub fn reference_span(cigar: &[Cigar], pos: i64, span_ops: SpanOps) -> (i64, i64) {
+    let start = pos + 1;
+    let length: i64 = cigar.iter()
+        .filter(|&op| span_ops.contains(op))
+        .map(|op| op.len() as i64)
+        .sum();
+    (start, start - 1 + length)
+}
+
+
+/// Lengths of leading and trailing soft clips, hard clips outside of them are ignored.
+///
+/// ```
+/// use rust_htslib::bam::record::Cigar;
+/// use seq2c_rs::soft_clips;
+///
+/// let cigar = [Cigar::HardClip(3), Cigar::SoftClip(5), Cigar::Match(10), Cigar::SoftClip(2)];
+/// assert_eq!(soft_clips(&cigar), (5, 2));
+/// assert_eq!(soft_clips(&[Cigar::Match(10)]), (0, 0));
+/// ```
+pub fn soft_clips(cigar: &[Cigar]) -> (i64, i64) {
+    let clip = |op: Option<&Cigar>| match op {
+        Some(Cigar::SoftClip(l)) => *l as i64,
+        _ => 0,
+    };
+    let mut ops = cigar.iter().filter(|op| !matches!(op, Cigar::HardClip(_)));
+    let leading = clip(ops.next());
+    let trailing = clip(ops.next_back());
+    (leading, trailing)
+}
+
+
+/// Collects 0-based half-open reference blocks covered by the read according to span operations.
+///
+/// Aligned bases with quality below `min_base_quality` are left out, deletions and skips have no quality and are kept
+/// unless a deletion is longer than `max_deletion`. Records without qualities (QUAL `*`, an empty slice or `0xFF`)
+/// have all bases passing.
+///
+/// ```
+/// use rust_htslib::bam::record::{Cigar, CigarString};
+/// use rust_htslib::bam::Record;
+/// use seq2c_rs::{aligned_blocks, SpanOps};
+///
+/// let cigar = [Cigar::Match(10), Cigar::Del(50), Cigar::Match(10), Cigar::Del(51), Cigar::Match(10)];
+/// let mut blocks = Vec::new();
+/// aligned_blocks(&cigar, 100, SpanOps::default(), &[30; 30], 0, Some(50), &mut blocks);
+/// assert_eq!(blocks, vec![(100, 110), (110, 160), (160, 170), (221, 231)]);
+///
+/// let mut qual = [30; 10];
+/// qual[4] = 10;
+/// aligned_blocks(&[Cigar::Match(10)], 100, SpanOps::default(), &qual, 20, None, &mut blocks);
+/// assert_eq!(blocks, vec![(100, 104), (105, 110)]);
+///
+/// // Records with QUAL `*`, without sequence or with a sequence and missing qualities
+/// let cigar = CigarString(vec![Cigar::Match(10)]);
+/// let mut record = Record::new();
+/// record.set(b"read", Some(&cigar), b"", &[]);
+/// aligned_blocks(&record.cigar(), 100, SpanOps::default(), record.qual(), 20, None, &mut blocks);
+/// assert_eq!(blocks, vec![(100, 110)]);
+/// record.set(b"read", Some(&cigar), b"ACGTACGTAC", &[0xFF; 10]);
+/// aligned_blocks(&record.cigar(), 100, SpanOps::default(), record.qual(), 20, None, &mut blocks);
+/// assert_eq!(blocks, vec![(100, 110)]);
+/// ```
+pub fn aligned_blocks(cigar: &[Cigar], pos: i64, span_ops: SpanOps, qual: &[u8], min_base_quality: u8, max_deletion: Option<u32>, blocks: &mut Vec<(i64, i64)>) {
+    blocks.clear();
+    // BAM stores a missing QUAL as 0xFF for every base
+    let min_base_quality = if qual.first().is_none_or(|&first| first == 0xFF) { 0 } else { min_base_quality };
+    let mut pos = pos;
+    let mut query_pos = 0;
+    for op in cigar.iter() {
+        match op {
+            Cigar::Match(l) | Cigar::Equal(l) | Cigar::Diff(l) => {
+                if span_ops.contains(op) {
+                    if min_base_quality == 0 {
+                        blocks.push((pos, pos + *l as i64));
+                    } else {
+                        // Split aligned block into runs of bases passing quality threshold
+                        let mut run_start = None;
+                        for i in 0..*l as usize {
+                            match (qual[query_pos + i] >= min_base_quality, run_start) {
+                                (true, None) => run_start = Some(pos + i as i64),
+                                (false, Some(begin)) => {
+                                    blocks.push((begin, pos + i as i64));
+                                    run_start = None;
+                                },
+                                _ => (),
+                            }
+                        }
+                        if let Some(begin) = run_start {
+                            blocks.push((begin, pos + *l as i64));
+                        }
+                    }
+                }
+                pos += *l as i64;
+                query_pos += *l as usize;
+            },
+            Cigar::Del(l) | Cigar::RefSkip(l) => {
+                let gap = matches!(op, Cigar::Del(_)) && max_deletion.is_some_and(|max_deletion| *l > max_deletion);
+                if span_ops.contains(op) && !gap {
+                    blocks.push((pos, pos + *l as i64));
+                }
+                pos += *l as i64;
+            },
+            Cigar::Ins(l) | Cigar::SoftClip(l) => query_pos += *l as usize,
+            _ => (),
+        }
+    }
+}
+
+
+/// Collects 1-based inclusive spans of deletions longer than `max_length` inside the span of [`reference_span`].
+///
+/// Positions advance over `span_ops` operations only, like the span, and nothing is collected
+/// when deletions aren't part of it.
+///
+/// ```
+/// use rust_htslib::bam::record::Cigar;
+/// use seq2c_rs::{deletion_gaps, SpanOps};
+///
+/// let cigar = [Cigar::Match(10), Cigar::Del(50), Cigar::Match(10), Cigar::Del(51), Cigar::Match(10)];
+/// let mut gaps = Vec::new();
+/// deletion_gaps(&cigar, 100, SpanOps::default(), 50, &mut gaps);
+/// assert_eq!(gaps, vec![(171, 221)]); // a deletion of exactly max_length stays covered
+/// deletion_gaps(&cigar, 100, SpanOps::default(), 49, &mut gaps);
+/// assert_eq!(gaps, vec![(111, 160), (171, 221)]);
+/// deletion_gaps(&cigar, 100, "M".parse().unwrap(), 49, &mut gaps);
+/// assert!(gaps.is_empty());
+/// ```
+pub fn deletion_gaps(cigar: &[Cigar], pos: i64, span_ops: SpanOps, max_length: u32, gaps: &mut Vec<(i64, i64)>) {
+    gaps.clear();
+    let mut pos = pos + 1;
+    for op in cigar.iter().filter(|&op| span_ops.contains(op)) {
+        if let Cigar::Del(l) = op {
+            if *l > max_length {
+                gaps.push((pos, pos + *l as i64 - 1));
+            }
+        }
+        pos += op.len() as i64;
+    }
+}
+
+
+/// Cluster index of every end-inclusive span, spans overlapping the cluster by at least `min_overlap` bases join it.
+///
+/// Spans are clustered in order of their start, clusters are numbered in the same order.
+///
+/// ```
+/// use seq2c_rs::collapse_overlapping;
+///
+/// let spans = [(100, 200), (150, 300), (290, 400), (500, 600)];
+/// assert_eq!(collapse_overlapping(&spans, 20), vec![0, 0, 1, 2]);
+/// assert_eq!(collapse_overlapping(&spans, 10), vec![0, 0, 0, 1]);
+/// ```
+pub fn collapse_overlapping(spans: &[(i64, i64)], min_overlap: i64) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..spans.len()).collect();
+    order.sort_by_key(|&i| spans[i]);
+    let mut clusters = vec![0; spans.len()];
+    let mut cluster = 0;
+    let mut cluster_end = i64::MIN;
+    for (n, &i) in order.iter().enumerate() {
+        let (start, end) = spans[i];
+        if n > 0 && cluster_end - start + 1 < min_overlap {
+            cluster += 1;
+            cluster_end = i64::MIN;
+        }
+        cluster_end = cluster_end.max(end);
+        clusters[i] = cluster;
+    }
+    clusters
+}
+
+
+/// Part of the end-inclusive `[first, last]` inside a contig, `None` if nothing is left.
+///
+/// Positions `0` to `contig_length` are inside the contig, the same bounds as of bed regions. Flanks are
+/// clipped with it before they're added to interval trees, which only take non-negative `i32` coordinates.
+///
+/// ```
+/// use seq2c_rs::clip_to_contig;
+///
+/// assert_eq!(clip_to_contig(-50, -1, 1000), None);           // left flank of a region at the contig start
+/// assert_eq!(clip_to_contig(-40, 9, 1000), Some((0, 9)));    // clipped at the contig start
+/// assert_eq!(clip_to_contig(981, 1030, 1000), Some((981, 1000))); // clipped at the contig end
+/// assert_eq!(clip_to_contig(1001, 1050, 1000), None);        // right flank of a region at the contig end
+/// assert_eq!(clip_to_contig(100, 200, 1000), Some((100, 200)));
+/// assert_eq!(clip_to_contig(100, i64::MAX, i64::MAX), Some((100, i32::MAX as i64)));
+/// ```
+pub fn clip_to_contig(first: i64, last: i64, contig_length: i64) -> Option<(i64, i64)> {
+    let (first, last) = (first.max(0), last.min(contig_length).min(MAX_POSITION));
+    (first <= last).then_some((first, last))
+}
+
+
+/// Flag bits of reads skipped by default: unmapped, secondary, QC fail and supplementary.
+///
+/// Same set as skipped by samtools/mosdepth coverage, duplicates are counted.
+pub const DEFAULT_EXCLUDE_FLAGS: u16 = 0x4 | 0x100 | 0x200 | 0x800;
+
+/// Largest position interval trees can hold, their coordinates are `i32`.
+pub const MAX_POSITION: i64 = i32::MAX as i64;
+
+/// Contigs of the bam header longer than `MAX_POSITION` as name and length.
+///
+/// Regions and reads on them can't be indexed without truncating positions, so they have to be rejected.
+///
+/// ```
+/// use rust_htslib::bam;
+/// use seq2c_rs::oversized_contigs;
+///
+/// let sam = "@SQ\tSN:chr1\tLN:1000\n@SQ\tSN:chr1H\tLN:3000000000\n";
+/// let path = std::env::temp_dir().join("seq2c-rs-oversized-doctest.sam");
+/// std::fs::write(&path, sam).unwrap();
+///
+/// let reader = bam::Reader::from_path(&path).unwrap();
+/// assert_eq!(oversized_contigs(bam::Read::header(&reader)), vec![(String::from("chr1H"), 3_000_000_000)]);
+/// std::fs::remove_file(&path).unwrap();
+/// ```
+pub fn oversized_contigs(header: &bam::HeaderView) -> Vec<(String, u64)> {
+    (0..header.target_count())
+        .map(|tid| (String::from_utf8_lossy(header.tid2name(tid)).to_string(), header.target_len(tid).unwrap_or(0)))
+        .filter(|&(_, length)| length > MAX_POSITION as u64)
+        .collect()
+}
+
+
+/// Start offsets of occurrences of `motif` in `seq` on either strand, sorted and distinct.
+///
+/// Bases are compared case-insensitively, so soft-masked sequence matches too. Occurrences of the
+/// reverse complement are reported at their leftmost base like forward ones, a palindromic motif
+/// such as `CG` is found once per site. The scan compares every offset, so it takes time
+/// proportional to the sequence length times the motif length.
+///
+/// ```
+/// use seq2c_rs::motif_sites;
+///
+/// assert_eq!(motif_sites(b"ACGTTcgNCG", b"CG"), vec![1, 5, 8]);
+/// assert_eq!(motif_sites(b"AAGCTTGG", b"CCA"), vec![5]); // TGG is the reverse complement
+/// assert_eq!(motif_sites(b"CG", b"CGA"), vec![]);
+/// ```
+pub fn motif_sites(seq: &[u8], motif: &[u8]) -> Vec<i64> {
+    if motif.is_empty() {
+        return Vec::new();
+    }
+    let reverse: Vec<u8> = motif.iter().rev().map(|base| match base.to_ascii_uppercase() {
+        b'A' => b'T',
+        b'C' => b'G',
+        b'G' => b'C',
+        b'T' => b'A',
+        other => other,
+    }).collect();
+    seq.windows(motif.len()).enumerate()
+        .filter(|(_, window)| window.eq_ignore_ascii_case(motif) || window.eq_ignore_ascii_case(&reverse))
+        .map(|(offset, _)| offset as i64)
+        .collect()
+}
+
+
+/// Shannon entropy of the per-base depth distribution given as disjoint `(start, end, depth)` runs,
+/// divided by its maximum so 1 is perfectly even coverage and 0 is all reads on a single base.
+///
+/// `None` without any depth or with fewer than two bases, where the distribution has no shape.
+///
+/// ```
+/// use seq2c_rs::depth_entropy;
+///
+/// let rounded = |runs: &[(i64, i64, u32)]| depth_entropy(runs).map(|entropy| (entropy * 1e4).round() / 1e4);
+/// assert_eq!(rounded(&[(0, 100, 30)]), Some(1.0));
+/// assert_eq!(rounded(&[(0, 50, 30), (50, 100, 0)]), Some(0.8495)); // half of the bases covered
+/// assert_eq!(rounded(&[(0, 1, 30), (1, 100, 0)]), Some(0.0));
+/// assert_eq!(depth_entropy(&[(0, 100, 0)]), None);
+/// ```
+pub fn depth_entropy(runs: &[(i64, i64, u32)]) -> Option<f64> {
+    let bases: i64 = runs.iter().map(|&(start, end, _)| end - start).sum();
+    let total: f64 = runs.iter().map(|&(start, end, depth)| (end - start) as f64 * depth as f64).sum();
+    if bases < 2 || total == 0.0 {
+        return None;
+    }
+    let entropy: f64 = runs.iter()
+        .filter(|&&(_, _, depth)| depth > 0)
+        .map(|&(start, end, depth)| {
+            let p = depth as f64 / total;
+            -((end - start) as f64) * p * p.ln()
+        })
+        .sum();
+    Some(entropy / (bases as f64).ln())
+}
+
+
+/// Contig names of the same sequence: equal names, or mitochondrial contig in any of the
+/// `M`, `MT`, `chrM`, `chrMT` spellings used by different references.
+///
+/// ```
+/// use seq2c_rs::same_contig;
+///
+/// for a in ["M", "MT", "chrM", "chrMT"] {
+///     for b in ["M", "MT", "chrM", "chrMT"] {
+///         assert!(same_contig(a, b));
+///     }
+/// }
+/// assert!(same_contig("chr1", "chr1"));
+/// assert!(!same_contig("chr1", "1")); // only mitochondrial names are aliased
+/// assert!(!same_contig("chrM", "chrMX"));
+/// ```
+pub fn same_contig(a: &str, b: &str) -> bool {
+    let mitochondrial = |name: &str| matches!(name, "M" | "MT" | "chrM" | "chrMT");
+    a == b || (mitochondrial(a) && mitochondrial(b))
+}
+
+
+/// Per-base depth of a region stored as runs of equal depth, memory grows with the number of
+/// depth changes instead of the region length.
+///
+/// Blocks are added as 0-based half-open offsets into the region and clipped to it. Depth before
+/// the offset passed to `settle` is compacted into runs, so for reads in coordinate order only
+/// reads overlapping the current position keep uncompacted changes. Blocks before the settled
+/// offset are still counted correctly, they only cost a rebuild of the runs.
+///
+/// ```
+/// use seq2c_rs::RunLengthDepth;
+///
+/// let mut depth = RunLengthDepth::new(10);
+/// depth.add(0, 4);
+/// depth.add(2, 6);
+/// depth.settle(2);
+/// depth.add(8, 20); // clipped to the region
+/// assert_eq!(depth.runs(), vec![(0, 2, 1), (2, 4, 2), (4, 6, 1), (6, 8, 0), (8, 10, 1)]);
+/// assert_eq!(depth.ranges_at_least(2), vec![(2, 4)]);
+/// assert_eq!(depth.ranges_at_least(1), vec![(0, 6), (8, 10)]);
+/// depth.add(0, 1); // before the settled offset
+/// assert_eq!(depth.runs()[0], (0, 1, 2));
+/// assert_eq!(depth.depths_at(&[0, 3, 7, 9]), vec![2, 2, 0, 1]);
+/// let mut even = RunLengthDepth::new(4);
+/// even.add(0, 4);
+/// assert_eq!(even.sd(), Some(0.0));
+/// assert_eq!(RunLengthDepth::new(0).sd(), None);
+/// ```
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RunLengthDepth {
+    length: i64,
+    runs: Vec<(i64, u32)>, // (run end, depth) of offsets before settled, runs start where the previous one ended
+    settled: i64,
+    current: i64, // depth at settled before deltas at it are applied
+    deltas: std::collections::BTreeMap<i64, i64>, // depth changes at offsets from settled on
+}
+
+impl RunLengthDepth {
+    pub fn new(length: usize) -> RunLengthDepth {
+        RunLengthDepth { length: length as i64, ..RunLengthDepth::default() }
+    }
+
+    pub fn len(&self) -> usize {
+        self.length as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+
+    /// Adds one read over `[from, to)`.
+    pub fn add(&mut self, from: i64, to: i64) {
+        let (from, to) = (from.max(0), to.min(self.length));
+        if from >= to {
+            return;
+        }
+        if from < self.settled {
+            self.unsettle();
+        }
+        *self.deltas.entry(from).or_insert(0) += 1;
+        *self.deltas.entry(to).or_insert(0) -= 1;
+    }
+
+    /// Compacts depth before `until`, blocks added later are expected to start at or after it.
+    pub fn settle(&mut self, until: i64) {
+        let until = until.min(self.length);
+        while let Some(entry) = self.deltas.first_entry() {
+            if *entry.key() >= until {
+                break;
+            }
+            let (pos, delta) = entry.remove_entry();
+            self.push_run(pos, self.current);
+            self.current += delta;
+        }
+        self.push_run(until, self.current);
+    }
+
+    fn push_run(&mut self, end: i64, depth: i64) {
+        if end <= self.settled {
+            return;
+        }
+        match self.runs.last_mut() {
+            Some(last) if last.1 as i64 == depth => last.0 = end,
+            _ => self.runs.push((end, depth as u32)),
+        }
+        self.settled = end;
+    }
+
+    // Turns runs back into depth changes, so a block before the settled offset can be added
+    fn unsettle(&mut self) {
+        let mut previous = 0;
+        let mut start = 0;
+        for &(end, depth) in &self.runs {
+            if depth as i64 != previous {
+                *self.deltas.entry(start).or_insert(0) += depth as i64 - previous;
+                previous = depth as i64;
+            }
+            start = end;
+        }
+        if self.current != previous {
+            *self.deltas.entry(self.settled).or_insert(0) += self.current - previous;
+        }
+        self.deltas.retain(|_, delta| *delta != 0);
+        self.runs.clear();
+        (self.settled, self.current) = (0, 0);
+    }
+
+    /// All runs as `(start, end, depth)` covering the whole region, runs without reads included.
+    pub fn runs(&self) -> Vec<(i64, i64, u32)> {
+        let mut runs = Vec::new();
+        let mut start = 0;
+        let mut push = |end: i64, depth: i64, runs: &mut Vec<(i64, i64, u32)>| {
+            if end <= start {
+                return;
+            }
+            match runs.last_mut() {
+                Some(last) if last.2 as i64 == depth => last.1 = end,
+                _ => runs.push((start, end, depth as u32)),
+            }
+            start = end;
+        };
+        for &(end, depth) in &self.runs {
+            push(end, depth as i64, &mut runs);
+        }
+        let mut current = self.current;
+        for (&pos, &delta) in &self.deltas {
+            push(pos.min(self.length), current, &mut runs);
+            current += delta;
+        }
+        push(self.length, current, &mut runs);
+        runs
+    }
+
+    /// Ranges of offsets with depth of at least `min_depth` as `[start, end)`, adjacent runs are joined into one range.
+    pub fn ranges_at_least(&self, min_depth: u32) -> Vec<(i64, i64)> {
+        let mut ranges: Vec<(i64, i64)> = Vec::new();
+        for (start, end, depth) in self.runs() {
+            if depth < min_depth {
+                continue;
+            }
+            match ranges.last_mut() {
+                Some(last) if last.1 == start => last.1 = end,
+                _ => ranges.push((start, end)),
+            }
+        }
+        ranges
+    }
+
+    /// Depth at each of the ascending `offsets`, offsets outside of the region have depth 0.
+    pub fn depths_at(&self, offsets: &[i64]) -> Vec<u32> {
+        let runs = self.runs();
+        let mut run = 0;
+        offsets.iter().map(|&offset| {
+            while run < runs.len() && runs[run].1 <= offset {
+                run += 1;
+            }
+            runs.get(run).filter(|&&(start, _, _)| start <= offset).map_or(0, |&(_, _, depth)| depth)
+        }).collect()
+    }
+
+    /// Population standard deviation of depth over all bases, `None` for an empty region.
+    pub fn sd(&self) -> Option<f64> {
+        if self.length == 0 {
+            return None;
+        }
+        let runs = self.runs();
+        let n = self.length as f64;
+        let mean = runs.iter().map(|&(start, end, depth)| (end - start) as f64 * depth as f64).sum::<f64>() / n;
+        let variance = runs.iter().map(|&(start, end, depth)| (end - start) as f64 * (depth as f64 - mean).powi(2)).sum::<f64>() / n;
+        Some(variance.sqrt())
+    }
+
+    /// Mean depth of consecutive bins of `window` bases as `(start, length, mean)`, the last bin is clipped to the region.
+    pub fn window_means(&self, window: i64) -> Vec<(i64, i64, f64)> {
+        let runs = self.runs();
+        let mut bins = Vec::new();
+        let mut run = 0;
+        let mut bin_start = 0;
+        while bin_start < self.length {
+            let bin_end = (bin_start + window).min(self.length);
+            let mut sum = 0.0;
+            while run < runs.len() && runs[run].0 < bin_end {
+                let (start, end, depth) = runs[run];
+                sum += (end.min(bin_end) - start.max(bin_start)) as f64 * depth as f64;
+                if end > bin_end {
+                    break;
+                }
+                run += 1;
+            }
+            bins.push((bin_start, bin_end - bin_start, sum / (bin_end - bin_start) as f64));
+            bin_start = bin_end;
+        }
+        bins
+    }
+}
@@ -1,40 +1,490 @@
-use std::io::BufReader;
+use std::io::{BufRead as _, BufReader, BufWriter, Read as _, Write};
 use std::fs::File;
 use std::thread::available_parallelism;
 
-use clap::{Parser};
+use clap::{Args, CommandFactory, FromArgMatches, Parser, Subcommand};
 
-use rust_htslib::{bam, bam::Read, bam::record::Cigar};
+use rust_htslib::{bam, bam::Read, bcf, bcf::Read as _, bgzf, faidx};
 use rust_htslib::bam::ext::BamRecordExtensions;
 
 use bio::io::bed;
 use bio::bio_types::genome::AbstractInterval;
+use bio::bio_types::strand::Strand;
 
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+
+mod output;
+mod panel_index;
+use panel_index::{load_index, save_index, IndexedRegion, PanelIndex};
+use output::{append_tsv, json_string, merge_reports, read_baseline_depths, report_stats, OutputFormat, FORMAT_VERSION, Table, Value::{Empty, Float, Int, Missing, Text}};
 
 use coitrees::*;
-use rustc_hash::FxHashMap;
+use seq2c_rs::{add_coverage, CoverageCalculator, Region, aligned_blocks, calculate_coverage, deletion_gaps, clip_to_contig, collapse_overlapping, depth_entropy, same_contig, merge_intervals, motif_sites, overlap_share, oversized_contigs, reference_span, soft_clips, RunLengthDepth, SpanOps, DEFAULT_EXCLUDE_FLAGS, MAX_POSITION};
+use rustc_hash::{FxHashMap, FxHashSet};
 use indexmap::IndexMap;
 use fnv::FnvBuildHasher;
 
 type FnvIndexMap<K, V> = IndexMap<K, V, FnvBuildHasher>;
 
 
+// Options of a count are also accepted without the subcommand, so `seq2c-rs -b ... -N ... -p ...` keeps working
 #[derive(Parser)]
 #[command(name = "seq2c-rs")]
 #[command(version)]
 #[command(about = "Counts bam coverage of a bed file", long_about = None)]
+#[command(args_conflicts_with_subcommands = true)]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[command(flatten)]
+    count: Option<CountArgs>,
+}
+
+
+#[derive(Subcommand)]
+enum Command {
+    #[command(about = "Counts bam coverage of a bed file, the default without a subcommand")]
+    Count(Box<CountArgs>),
+    #[command(about = "Merges amplicon MeanDepth of tsv reports into an amplicon by sample matrix")]
+    Merge(MergeArgs),
+    #[command(about = "Prints per-sample summary of tsv reports")]
+    Stats(StatsArgs),
+}
+
+
+#[derive(Args)]
+struct MergeArgs {
+    #[arg(short='o', long, help="path to write amplicon by sample depth matrix")]
+    output: String,
+    #[arg(required=true, help="tsv reports of seq2c-rs, all reports have to be from the same panel")]
+    reports: Vec<String>,
+    #[arg(long, default_value="Amplicon", help="Tag column value of region rows")]
+    amplicon_tag: String,
+    #[arg(long, default_value="#", help="prefix of comment lines skipped in reports")]
+    comment_prefix: String,
+    #[arg(long, help="directory for depth columns of the reports, keeps only the first sample in memory for large cohorts, files are removed when the merge ends")]
+    tmpdir: Option<String>,
+    #[arg(long, help="overwrite existing output file")]
+    force: bool,
+}
+
+
+#[derive(Args)]
+struct StatsArgs {
+    #[arg(required=true, help="tsv reports of seq2c-rs, reports grown with --append have a row for each sample")]
+    reports: Vec<String>,
+    #[arg(long, default_value="Amplicon", help="Tag column value of region rows")]
+    amplicon_tag: String,
+    #[arg(long, default_value="#", help="prefix of comment lines skipped in reports")]
+    comment_prefix: String,
+}
+
+
+#[derive(Args)]
+struct CountArgs {
     #[arg(short='b', long, help="path to the bam file")]
     bam: String,
-    #[arg(short='N', long, help="file name to use in output file")]
-    sample_name: String,
-    #[arg(short='p',long, help="path to the bed file")]
-    bed: String,
+    #[arg(short='N', long, required_unless_present="sample_from_bam", help="file name to use in output file")]
+    sample_name: Option<String>,
+    #[arg(long, help="use the SM of @RG header lines of the bam as sample name, fails if they name several samples unless --split-by-rg is set, --sample-name is used if the header has no SM")]
+    sample_from_bam: bool,
+    #[arg(short='p',long, required_unless_present_any=["regions_from_bam", "refflat"], help="path to the bed file, '-' is stdin, can be repeated to merge several bed files into one panel")]
+    bed: Vec<String>,
     #[arg(long, default_value="true", help="(default: true) enable outputting fragment length - 1, same as perl version of seq2c")]
     mimic_perl_output: bool,
-    #[arg(long="threads",default_value="0",help="number of threads to use for bam/cram decompression, default 0 = automatically detect number of cores")]
+    #[arg(long="threads",default_value="0",help="number of threads to use for bam/cram decompression and tsv report formatting, default 0 = automatically detect number of cores")]
     threads: usize,
+    #[arg(long, help="treat warnings as errors: bed contig missing from bam header, unsorted bed, bed record without name, identical bed records, regions of different genes overlapping, no reads on target, bam without alignment records")]
+    strict: bool,
+    #[arg(long, help="minimum mean depth of a gene, adds Status column with PASS/FAIL to Whole-Gene rows")]
+    gene_min_depth: Option<f64>,
+    #[arg(long, value_parser=parse_flags, help="only count reads that have all of these flag bits set (decimal or 0x hex)")]
+    require_flags: Option<u16>,
+    #[arg(long, value_parser=parse_flags, help="skip reads with any of these flag bits set (decimal or 0x hex), replaces default of 0xF04 (unmapped, secondary, QC fail, supplementary)")]
+    exclude_flags: Option<u16>,
+    #[arg(long, help="count supplementary alignments, removes 0x800 from the exclude mask")]
+    count_supplementary: bool,
+    #[arg(long, help="validate bed and bam header and print what would be processed without reading alignments or writing any output, exits with non-zero code if any warning was raised")]
+    dry_run: bool,
+    #[arg(long, requires="vcf_output", help="path to the vcf/bcf file, depth is reported at every variant position")]
+    vcf: Option<String>,
+    #[arg(long, help="path to write depth at vcf variant positions (CHROM, POS, DEPTH)")]
+    vcf_output: Option<String>,
+    #[arg(long, help="path to write bed of contiguous target runs with depth of at least --callable-depth, enables per-base depth")]
+    callable: Option<String>,
+    #[arg(long, default_value="10", help="minimum per-base depth of callable runs")]
+    callable_depth: u32,
+    #[arg(long, help="count reads only on regions with the same strand as the read alignment (bed column 6), regions with '.' strand count reads from both strands")]
+    strand_match: bool,
+    #[arg(long, help="path to the indexed reference fasta or only its .fai index, bed regions are checked against contig lengths")]
+    reference: Option<String>,
+    #[arg(long, requires="reference", help="exclude reference N bases from region and gene length")]
+    exclude_n: bool,
+    #[arg(long, value_name="SEQ", requires="reference", help="motif of A, C, G and T bases, e.g. CG, adds MotifSites and MotifDepth columns with occurrences on either strand in the reference and mean per-base depth over their bases, enables per-base depth")]
+    motif: Option<String>,
+    #[arg(long, help="path to write amplicons as bed with mean depth in the score column (clamped to 0-1000), paths ending with .gz are bgzipped and get a tabix index")]
+    amplicon_bed: Option<String>,
+    #[arg(long, help="prepend comment lines with seq2c-rs and htslib versions, command line and input files modification times to the output")]
+    emit_provenance: bool,
+    #[arg(long, default_value="#", help="prefix of provenance comment lines")]
+    comment_prefix: String,
+    #[arg(long, help="overwrite existing output files")]
+    force: bool,
+    #[arg(long, help="delimiter splitting region names into fields, e.g. '|' for gene|transcript|exon names")]
+    name_delimiter: Option<String>,
+    #[arg(long, default_value="0", requires="name_delimiter", help="0-based index of the name field used as gene for aggregation")]
+    aggregate_field: usize,
+    #[arg(long, default_value="Whole-Gene", help="Tag column value of gene aggregate rows")]
+    gene_tag: String,
+    #[arg(long, default_value="Amplicon", help="Tag column value of region rows")]
+    amplicon_tag: String,
+    #[arg(long, help="add ZeroBases column with number of bases without coverage, for genes counted over union of its regions, enables per-base depth")]
+    zero_bases: bool,
+    #[arg(long, default_value="M,D", help="comma separated CIGAR operations counted as covered reference: any of M,=,X,D,N, default M,D is the same as perl version of seq2c")]
+    span_ops: SpanOps,
+    #[arg(long, default_value="0", help="minimum base quality for a base to add to per-base depth, deletions are not filtered, ignored without per-base depth")]
+    min_base_quality: u8,
+    #[arg(long, help="count only the first of reads with identical aligned start, end, strand and mate position, requires coordinate sorted input, doesn't replace UMI aware deduplication")]
+    dedup_by_position: bool,
+    #[arg(long, default_value="0", help="skip reads with mapping quality below this value")]
+    min_mapq: u8,
+    #[arg(long, help="weight coverage of each read by min(mapq, 60) / 60 in MeanDepth, per-base depth is not weighted")]
+    mapq_weight: bool,
+    #[arg(long, help="add MeanInsertSize and MedianInsertSize columns to Whole-Gene rows, counted once per properly paired fragment from the leftmost mate")]
+    insert_stats: bool,
+    #[arg(long, value_name="BOOL", help="(default: true) count deleted reference bases (D) as covered, overrides --span-ops")]
+    del_covered: Option<bool>,
+    #[arg(long, value_name="BOOL", help="(default: false) count skipped reference bases (N) as covered, overrides --span-ops")]
+    skip_covered: Option<bool>,
+    #[arg(long, value_name="BP", help="count deletions longer than this many bases as gaps that are not covered instead of covered reference, in MeanDepth and per-base depth, shorter deletions stay covered")]
+    del_gap: Option<u32>,
+    #[arg(long, value_delimiter=',', default_value="tsv", help="comma separated report formats: tsv, json")]
+    format: Vec<OutputFormat>,
+    #[arg(short='o', long, value_delimiter=',', allow_hyphen_values=true, help="comma separated report paths, one for each --format, '-' is stdout (default: stdout for a single format)")]
+    output: Vec<String>,
+    #[arg(long, conflicts_with="bed", help="scan the coordinate sorted bam for runs with depth of at least --island-depth and report them as amplicons instead of bed regions")]
+    regions_from_bam: bool,
+    #[arg(long, value_name="PATH", conflicts_with_all=["bed", "regions_from_bam"], help="read targets from a UCSC refFlat or refGene table instead of --bed, exons of all transcripts of a gene are merged into its regions")]
+    refflat: Option<String>,
+    #[arg(long, default_value="10", value_parser=clap::value_parser!(u32).range(1..), help="minimum per-base depth of coverage islands found with --regions-from-bam")]
+    island_depth: u32,
+    #[arg(long, value_enum, help="add Log2Ratio column with amplicon depth relative to the sample: sample-mean divides by mean depth over all amplicons")]
+    normalize: Option<Normalization>,
+    #[arg(long, help="path to write amplicon by sample depth matrix (Gene, Chr, Start, End and a MeanDepth column named by the sample), rows are in report order")]
+    matrix: Option<String>,
+    #[arg(long, help="divide MeanDepth of each region by its bed score (e.g. expected depth), regions with missing, zero or non numeric score are not weighted")]
+    weight_from_score: bool,
+    #[arg(long, help="extend counted read span by leading and trailing soft clips projected onto the reference, clamped to the contig, per-base depth is not extended")]
+    include_softclip_span: bool,
+    #[arg(short='v', long, action=clap::ArgAction::Count, help="more detailed progress on stderr, -v adds per-chromosome panel counts")]
+    verbose: u8,
+    #[arg(long, help="leave Amplicon and Whole-Gene rows of genes without any counted reads out of the report, other outputs keep them")]
+    suppress_empty_genes: bool,
+    #[arg(long, value_enum, default_value="zero-half-open", help="coordinates of bed records: zero-half-open is standard bed, one-inclusive is converted to standard bed on load")]
+    bed_coords: BedCoords,
+    #[arg(long, help="report MeanDepth of Amplicon and Whole-Gene rows as log2(mean depth + --pseudocount), other columns and outputs keep raw depth")]
+    log2_depth: bool,
+    #[arg(long, default_value="1", requires="log2_depth", help="value added to mean depth before log2 with --log2-depth, has to be positive")]
+    pseudocount: f64,
+    #[arg(long, value_name="BP", value_parser=clap::value_parser!(u32).range(1..), help="add LeftFlankDepth and RightFlankDepth columns with mean depth of this many bases right outside of each amplicon")]
+    flank: Option<u32>,
+    #[arg(long, value_name="PATH", help="path to write amplicon by read group depth matrix with a MeanDepth column for each @RG of the bam header, counted in the same pass")]
+    split_by_rg: Option<String>,
+    #[arg(long, value_name="MEAN_DEPTH", help="exit with code 2 after writing all outputs if the sample mean depth over all amplicons is below this value")]
+    fail_below: Option<f64>,
+    #[arg(long, help="count unmapped reads among primary records (not secondary or supplementary) and show them with the unmapped fraction in the summary and manifest")]
+    output_unmapped_summary: bool,
+    #[arg(long, value_name="N", requires="gene_min_depth", help="exit with code 2 after writing all outputs if fewer than N Whole-Gene rows reach --gene-min-depth")]
+    min_genes_covered: Option<usize>,
+    #[arg(long, value_name="TSV", help="path to tab separated amplicon name to group map, adds a row for each group after all genes, amplicons missing from it are grouped as 'ungrouped'")]
+    group_map: Option<String>,
+    #[arg(long, default_value="Group", help="Tag column value of group aggregate rows")]
+    group_tag: String,
+    #[arg(long, help="leave out Whole-Gene rows of genes with a single amplicon, the Amplicon row has the same values except Status and gene only columns")]
+    no_amplicon_aggregate_when_single: bool,
+    #[arg(long, help="report Amplicon rows of a gene in bed order instead of sorted by name and position")]
+    keep_region_order: bool,
+    #[arg(long, value_name="BP", value_parser=clap::value_parser!(i64).range(1..), help="report amplicons of a gene overlapping by at least this many bases as one Amplicon row with depth counted over the merged span, counting of amplicons and genes doesn't change")]
+    collapse_amplicons: Option<i64>,
+    #[arg(long, value_name="BP", value_parser=clap::value_parser!(u32).range(1..), help="add Window rows after each amplicon with mean per-base depth in bins of this size, the last bin is clipped to the amplicon, enables per-base depth")]
+    window: Option<u32>,
+    #[arg(long, help="count empty bed records (start equals end) as the single base after start instead of skipping them")]
+    empty_as_single_base: bool,
+    #[arg(long, help="add LengthRaw (End - Start) and LengthPerl (End - Start + 1) columns next to Length regardless of --mimic-perl-output")]
+    both_lengths: bool,
+    #[arg(long, default_value="0", value_parser=clap::value_parser!(i64).range(0..=1), help="coordinates of Start and End columns of the report and matrices, 0 is bed-like 0-based half-open, 1 is 1-based inclusive (Start + 1), Length doesn't change")]
+    coord_base: i64,
+    #[arg(long, value_name="PATH", help="write a json manifest with inputs and their checksums, effective options, versions, timing and summary stats")]
+    manifest: Option<String>,
+    #[arg(long, value_name="BP", help="skip properly paired reads with absolute template length below this, other reads are not filtered")]
+    min_fragment_length: Option<u32>,
+    #[arg(long, value_name="BP", help="skip properly paired reads with absolute template length above this, other reads are not filtered")]
+    max_fragment_length: Option<u32>,
+    #[arg(long, help="with --min-fragment-length or --max-fragment-length also skip reads that are not properly paired")]
+    drop_unpaired: bool,
+    #[arg(long, value_name="PATH", conflicts_with="regions_from_bam", help="save the parsed bed regions to a binary index, so later runs over the same panel can skip bed parsing with --load-index")]
+    save_index: Option<String>,
+    #[arg(long, value_name="PATH", conflicts_with_all=["regions_from_bam", "save_index"], help="load regions from an index written by --save-index instead of parsing --bed, fails if bed files or options changed since it was saved")]
+    load_index: Option<String>,
+    #[arg(long, help="add DepthSD column with standard deviation of per-base depth of every amplicon, enables per-base depth")]
+    depth_sd: bool,
+    #[arg(long, help="add Entropy column to Whole-Gene rows with Shannon entropy of per-base depth over the gene divided by its maximum, 1 is perfectly even coverage, enables per-base depth")]
+    entropy: bool,
+    #[arg(long, help="add MidpointDepth column with number of reads covering the central base of every amplicon, a cheap success check without per-base depth")]
+    midpoint_depth: bool,
+    #[arg(long, value_name="BED", help="bed file of primer coordinates, read bases overlapping primers are not counted")]
+    primers: Option<String>,
+    #[arg(long, value_name="PATH", help="path to write merged non-overlapping target intervals as bed, named by gene if all merged regions belong to one gene, otherwise '.'")]
+    target_bed: Option<String>,
+    #[arg(long, help="only count properly paired reads, single-end reads and orphaned mates are skipped")]
+    paired_only: bool,
+    #[arg(long, value_name="BAM or TSV", help="control sample as a bam or a seq2c-rs report of the same panel, a bam can't be used with read options it isn't counted with, adds BaselineRatio column with amplicon depth divided by the baseline depth")]
+    baseline: Option<String>,
+    #[arg(long, value_name="TSV", help="tab separated gene and full gene length, adds TargetedFraction column to Whole-Gene rows with merged target bases divided by gene length")]
+    gene_lengths: Option<String>,
+    #[arg(long, help="append rows to existing tsv --output files with the same columns instead of overwriting them, e.g. to grow a cohort report sample by sample")]
+    append: bool,
+    #[arg(long, value_enum, default_value="span", help="span counts read bases over regions, start5 additionally adds StartCount column with reads whose strand-aware 5' end is inside the amplicon, unique-start adds UniqueStarts column with distinct start and strand pairs of reads on the amplicon")]
+    count_mode: CountMode,
+    #[arg(long, value_delimiter=',', value_parser=clap::value_parser!(u32).range(1..), help="comma separated ascending depths, e.g. 1,10,20,30,50,100, adds a column for each with fraction of bases with at least that depth to Amplicon and Whole-Gene rows, enables per-base depth")]
+    thresholds: Vec<u32>,
+    #[arg(long, help="add Rank column to Whole-Gene rows with percentile rank of the gene mean depth among Whole-Gene rows of the sample, 0 is the lowest depth")]
+    rank: bool,
+    #[arg(long, help="prepend comment lines with the report format version and --coord-base to tsv reports, json reports always have them as format_version and coord_base")]
+    report_format_version: bool,
+    #[arg(long, help="split read bases on overlapping amplicons among them by overlap instead of crediting shared bases to each of them, MeanDepth of genes and the sample isn't inflated by tiling")]
+    proportional_overlap: bool,
+    #[arg(long, value_name="FILE", help="gene names one per line in the order genes are reported in, genes missing from it follow in default order")]
+    gene_order: Option<String>,
+    #[arg(long, requires="gene_order", help="leave genes missing from --gene-order out of the report")]
+    drop_unlisted_genes: bool,
+    #[arg(long, value_delimiter=',', help="comma separated genes, only bed regions of these genes are counted and reported, fails if a gene is not in the bed")]
+    genes: Vec<String>,
+    #[arg(long, value_name="FILE", help="genes one per line to count and report, added to --genes")]
+    genes_file: Option<String>,
+    #[arg(long, help="add the number of amplicons and genes with at least --covered-breadth of their bases at --covered-depth or more to the summary, enables per-base depth")]
+    covered_summary: bool,
+    #[arg(long, default_value="0.9", requires="covered_summary", help="fraction of bases of an amplicon or gene that has to reach --covered-depth")]
+    covered_breadth: f64,
+    #[arg(long, default_value="20", value_parser=clap::value_parser!(u32).range(1..), requires="covered_summary", help="per-base depth counted as covered by --covered-summary")]
+    covered_depth: u32,
+}
+
+
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum BedCoords {
+    ZeroHalfOpen,
+    OneInclusive,
+}
+
+
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum CountMode {
+    Span,
+    Start5,
+    UniqueStart,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum Normalization {
+    SampleMean,
+}
+
+// Amplicon depth used instead of zero in Log2Ratio, keeps the ratio finite for amplicons without reads
+const LOG2_DEPTH_FLOOR: f64 = 0.01;
+// Same for baseline depth in BaselineRatio
+const BASELINE_DEPTH_FLOOR: f64 = 0.01;
+
+
+fn parse_flags(value: &str) -> Result<u16, String> {
+    let parsed = match value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")) {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => value.parse::<u16>(),
+    };
+    parsed.map_err(|e| format!("invalid flag mask '{value}': {e}"))
+}
+
+
+const FLAG_UNMAPPED: u16 = 0x4;
+const FLAG_SECONDARY: u16 = 0x100;
+const FLAG_DUPLICATE: u16 = 0x400;
+const FLAG_SUPPLEMENTARY: u16 = 0x800;
+
+// Read is counted if it has all required bits and none of the excluded ones.
+// Exclude mask is resolved in order: --exclude-flags (or the default), then bits
+// re-enabled by --count-* switches are removed, then bits from --require-flags are removed,
+// so an explicit request for a flag always wins over skipping it.
+struct FlagFilter {
+    require: u16,
+    exclude: u16,
+}
+
+impl FlagFilter {
+    fn new(cli: &CountArgs) -> FlagFilter {
+        let require = cli.require_flags.unwrap_or(0);
+        let mut exclude = cli.exclude_flags.unwrap_or(DEFAULT_EXCLUDE_FLAGS);
+        if cli.count_supplementary {
+            exclude &= !FLAG_SUPPLEMENTARY;
+        }
+        exclude &= !require;
+        FlagFilter { require, exclude }
+    }
+
+    fn passes(&self, flags: u16) -> bool {
+        flags & self.require == self.require && flags & self.exclude == 0
+    }
+}
+
+
+fn fail(message: &str) -> ! {
+    eprintln!("Error: {message}");
+    std::process::exit(1);
+}
+
+
+struct Warnings {
+    strict: bool,
+    raised: Cell<usize>,
+}
+
+impl Warnings {
+    // Under --strict every warning aborts the run with non-zero exit code
+    fn warn(&self, message: &str) {
+        if self.strict {
+            fail(message);
+        }
+        eprintln!("Warning: {message}");
+        self.raised.set(self.raised.get() + 1);
+    }
+
+    // What happens to an input record a warning is raised for
+    fn skipped(&self) -> &'static str {
+        if self.strict { "rejected" } else { "skipped" }
+    }
+}
+
+
+// Gzip or bgzip compressed beds are recognized by their magic bytes and decompressed by htslib
+fn open_bed(path: &str) -> bed::Reader<Box<dyn std::io::Read>> {
+    if path == "-" {
+        // Stdin can't be reopened after peeking, so only plain text is read from it
+        let mut stdin = BufReader::new(std::io::stdin().lock());
+        if stdin.fill_buf().is_ok_and(|bytes| bytes.starts_with(&[0x1f, 0x8b])) {
+            fail("compressed bed can't be read from stdin, decompress it first, e.g. with zcat");
+        }
+        return bed::Reader::new(Box::new(stdin));
+    }
+    bed::Reader::new(open_text(path))
+}
+
+fn open_text(path: &str) -> Box<dyn std::io::Read> {
+    let mut magic = [0u8; 2];
+    let gzipped = File::open(path).and_then(|mut file| file.read_exact(&mut magic)).is_ok() && magic == [0x1f, 0x8b];
+    if gzipped {
+        Box::new(BufReader::new(bgzf::Reader::from_path(path).expect("Error opening compressed bed file")))
+    } else {
+        Box::new(BufReader::new(File::open(path).expect("Error opening bed file")))
+    }
+}
+
+
+// Exons of a UCSC refFlat or refGene table as bed records named by gene, both have chrom to exonEnds in columns 3 to 11.
+// Exons of all transcripts of a gene on a chromosome are merged where they overlap or touch, so each base of the gene
+// is counted once and Whole-Gene rows cover the union of its transcripts. Strand is kept if all transcripts share it.
+fn read_refflat(path: &str, warnings: &Warnings) -> Vec<bed::Record> {
+    let mut genes = FnvIndexMap::<(String, String), (Vec<(i64, i64)>, Option<String>)>::default();
+    let mut chrom_order = Vec::new();
+    for (i, line) in BufReader::new(open_text(path)).lines().enumerate() {
+        let line = line.expect("Error reading refFlat file");
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split('\t').collect();
+        // refGene has a bin column first and the gene name in name2, refFlat starts with the gene name
+        let gene = match fields.len() {
+            11 => fields[0],
+            n if n >= 16 => fields[12],
+            _ => "",
+        };
+        let positions = |column: usize| fields.get(column).map_or(Ok(Vec::new()), |field| {
+            field.split(',').filter(|position| !position.is_empty()).map(str::parse::<i64>).collect::<Result<Vec<_>, _>>()
+        });
+        let exons = match (positions(9), positions(10)) {
+            (Ok(starts), Ok(ends)) if !gene.is_empty() && !starts.is_empty() && starts.len() == ends.len() && starts.iter().zip(&ends).all(|(start, end)| 0 <= *start && start < end) => {
+                starts.into_iter().zip(ends).collect::<Vec<_>>()
+            },
+            _ => {
+                warnings.warn(&format!("Malformed refFlat line {} in {path} is skipped, it needs 11 (refFlat) or at least 16 (refGene) columns with matching exon starts and ends", i + 1));
+                continue;
+            },
+        };
+        let (chrom, strand) = (fields[2].to_string(), fields[3].to_string());
+        if !chrom_order.contains(&chrom) {
+            chrom_order.push(chrom.clone());
+        }
+        let (gene_exons, gene_strand) = genes.entry((chrom, gene.to_string())).or_insert((Vec::new(), Some(strand.clone())));
+        gene_exons.extend(exons);
+        if gene_strand.as_ref() != Some(&strand) {
+            *gene_strand = None;
+        }
+    }
+    let mut records = Vec::new();
+    for chrom in &chrom_order {
+        let mut chrom_records = Vec::new();
+        for ((_, gene), (exons, strand)) in genes.iter_mut().filter(|((gene_chrom, _), _)| gene_chrom == chrom) {
+            for (start, end) in merge_intervals(exons) {
+                let mut record = bed::Record::new();
+                record.set_chrom(chrom);
+                record.set_start(start as u64);
+                record.set_end(end as u64);
+                record.set_name(gene);
+                record.set_score("0");
+                record.push_aux(strand.as_deref().unwrap_or("."));
+                chrom_records.push(record);
+            }
+        }
+        chrom_records.sort_by_key(|record| (record.start(), record.end()));
+        records.extend(chrom_records);
+    }
+    records
+}
+
+
+// Guess alignment file format from the first decompressed bytes
+fn detect_alignment_format(path: &str) -> &'static str {
+    let mut magic = [0u8; 4];
+    let read = bgzf::Reader::from_path(path).ok().and_then(|mut reader| reader.read_exact(&mut magic).ok());
+    match (read, &magic) {
+        (None, _) => "unknown",
+        (_, b"BAM\x01") => "BAM",
+        (_, b"CRAM") => "CRAM",
+        _ => "SAM",
+    }
+}
+
+
+// Baseline given as reads rather than a report, sam is only recognized by extension
+fn is_baseline_bam(path: &str) -> bool {
+    path.ends_with(".sam") || matches!(detect_alignment_format(path), "BAM" | "CRAM")
+}
+
+
+// Read options that change amplicon depth but aren't applied when a baseline bam is counted
+fn baseline_bam_conflicts(cli: &CountArgs) -> Vec<&'static str> {
+    [
+        (cli.require_flags.is_some(), "--require-flags"),
+        (cli.strand_match, "--strand-match"),
+        (cli.mapq_weight, "--mapq-weight"),
+        (cli.dedup_by_position, "--dedup-by-position"),
+        (cli.include_softclip_span, "--include-softclip-span"),
+        (cli.del_gap.is_some(), "--del-gap"),
+        (cli.paired_only, "--paired-only"),
+        (cli.min_fragment_length.is_some(), "--min-fragment-length"),
+        (cli.max_fragment_length.is_some(), "--max-fragment-length"),
+        (cli.primers.is_some(), "--primers"),
+        (cli.proportional_overlap, "--proportional-overlap"),
+    ].into_iter().filter(|&(set, _)| set).map(|(_, option)| option).collect()
 }
 
 
@@ -42,180 +492,2269 @@ struct Cli {
 struct RegionWithName {
     name: String,
     count: RefCell<i64>,
+    depth: RefCell<RunLengthDepth>, // per-base depth over [start, end) of the bed record, empty unless per-base depth is enabled
+    strand: Strand,
+    n_bases: i64, // reference N bases inside the region, only counted with --exclude-n
+    motif_sites: Vec<i64>, // start offsets of --motif occurrences inside the region
+    insert_sizes: RefCell<BTreeMap<i64, u64>>, // histogram of fragment lengths, only filled with --insert-stats
+    score_weight: f64, // MeanDepth divisor taken from bed score with --weight-from-score, otherwise 1
+    group_counts: RefCell<Vec<i64>>, // count of each read group, empty unless --split-by-rg is set
+    input_index: usize, // position of the record among all bed records
+    midpoint_count: Cell<i64>, // reads covering the central base, weighted like count
+    start_count: Cell<i64>, // reads with 5' end inside the region, only counted with --count-mode start5
+    unique_starts: Cell<i64>, // distinct read start and strand pairs, only counted with --count-mode unique-start
+    last_start: Cell<(i64, u8)>, // start of the latest counted reads and bit mask of their strands, earlier starts are final in a sorted bam
+}
+
+impl RegionWithName {
+    fn new(name: String, strand: Strand, depth_length: usize) -> RegionWithName {
+        RegionWithName {
+            name,
+            strand,
+            n_bases: 0,
+            motif_sites: Vec::new(),
+            score_weight: 1.0,
+            group_counts: RefCell::new(Vec::new()),
+            input_index: 0,
+            midpoint_count: Cell::new(0),
+            start_count: Cell::new(0),
+            unique_starts: Cell::new(0),
+            last_start: Cell::new((0, 0)),
+            insert_sizes: RefCell::new(BTreeMap::new()),
+            count: RefCell::new(0),
+            depth: RefCell::new(RunLengthDepth::new(depth_length)),
+        }
+    }
 }
 
-#[derive(Debug, PartialOrd, Ord, PartialEq, Eq)]
+#[derive(Debug)]
 struct OutputRegion {
+    gene: String, // aggregation key, the same as name unless --name-delimiter is set
     name: String,
     start: i64,
     end: i64,
-    count: i64
+    count: i64,
+    depth: RunLengthDepth,
+    n_bases: i64,
+    motif_sites: Vec<i64>,
+    insert_sizes: BTreeMap<i64, u64>,
+    score_weight: f64,
+    strand: Strand,
+    group_counts: Vec<i64>,
+    input_index: usize,
+    midpoint_count: i64,
+    start_count: i64,
+    unique_starts: i64,
 }
 
 
-fn calculate_coverage(a: std::ops::Range<i64>, b: std::ops::Range<i64>) -> i64 {
-    // Find the start and end of the intersection
-    let intersection_start = std::cmp::max(a.start, b.start);
-    let intersection_end = std::cmp::min(a.end, b.end);
-    
-    intersection_end - intersection_start + 1
-}
 
 
+// Merged 1-based inclusive primer spans by bam tid, primers of contigs missing from the bam are ignored
+fn read_primers(path: &str, bam_chroms: &[String]) -> Vec<Vec<(i64, i64)>> {
+    let mut primers = vec![Vec::new(); bam_chroms.len()];
+    for record in open_bed(path).records() {
+        let rec = record.unwrap_or_else(|e| fail(&format!("Malformed primer BED record in {path}: {e}")));
+        let tid = bam_chroms.iter().position(|name| name == rec.chrom()).or_else(|| bam_chroms.iter().position(|name| same_contig(name, rec.chrom())));
+        if let Some(tid) = tid {
+            primers[tid].push((rec.start() as i64, rec.end() as i64));
+        }
+    }
+    primers.into_iter()
+        .map(|mut spans| merge_intervals(&mut spans).into_iter().map(|(start, end)| (start + 1, end)).collect())
+        .collect()
+}
 
-fn update_node(start: i64, end: i64, interval: &IntervalNode<RegionWithName, u32>) {
-    let metadata = &interval.metadata;
-    if metadata.name != "." { //Skip calculation of coverage for unnamed regions
-        let mut count = metadata.count.borrow_mut(); //Mutable borrow, but happens only in one thread, so it's fine
-        *count += calculate_coverage(start..end, interval.first as i64..interval.last as i64);
+
+// Loads 1-based variant positions per chromosome, multiallelic sites are kept as one position
+fn read_variant_sites(path: &str) -> FnvIndexMap<String, Vec<i64>> {
+    let mut reader = bcf::Reader::from_path(path).expect("Error opening vcf file");
+    let mut sites = FnvIndexMap::<String, Vec<i64>>::default();
+    for r in reader.records() {
+        let record = r.expect("Error reading vcf record");
+        let rid = record.rid().expect("VCF record does not define chromosome");
+        let chrom = String::from_utf8_lossy(record.header().rid2name(rid).expect("VCF chromosome is missing from header"));
+        match sites.get_mut(chrom.as_ref()) {
+            Some(positions) => positions.push(record.pos() + 1),
+            None => { sites.insert(chrom.to_string(), vec![record.pos() + 1]); },
+        }
+    }
+    for positions in sites.values_mut() {
+        positions.sort_unstable();
+        positions.dedup();
     }
+    sites
 }
 
 
+// Amplicon read base counts of a baseline bam, or MeanDepth of a baseline report, by (chrom, start, end)
+enum Baseline {
+    Counts(FxHashMap<(String, i64, i64), f64>),
+    Depths(FxHashMap<(String, i64, i64), f64>),
+}
 
-fn main(){
-    let cli = Cli::parse();
-    let sample_name = cli.sample_name;
-    let mimic_perl_output = cli.mimic_perl_output;
-    eprintln!("Started");
 
-    let bam_threads = if cli.threads == 0 {
-            available_parallelism().expect("Wasn't able to automatically reconize number of threads, please set it by setting --threads argument manually").get()
-        } else {
-            cli.threads
-    };
-    eprintln!("Using {bam_threads} threads for reading bam file");
+// Mapping quality at which read coverage gets full weight with --mapq-weight
+const MAPQ_WEIGHT_CAP: u8 = 60;
 
-    let mut nodes: FxHashMap<String, Vec<Interval<RegionWithName>>> = FxHashMap::default();
-    let mut bed_map: FxHashMap<String, COITree<RegionWithName, u32>> = FxHashMap::default();
+// Read weight multiplier with --proportional-overlap, shares of a read base are kept to 1/1000
+const OVERLAP_SHARE_SCALE: i64 = 1000;
 
-    eprintln!("Reading bed file");
-    let mut bed_chrom_order = Vec::new();
-    let mut reader = File::open(cli.bed).map(BufReader::new).map(bed::Reader::new).unwrap();
-    for record in reader.records() {
-        let rec = record.expect("Error reading record.");
-        let node_vec = nodes.entry(rec.chrom().to_string()).or_default();
-        node_vec.push(
-                        Interval::new(rec.start() as i32, 
-                                        rec.end() as i32,
-                                        RegionWithName{ 
-                                            name:rec.name().expect("BED record does not define name").to_string(), 
-                                            count: RefCell::new(0)
-                                        }
-                                    )
-                        );
-        bed_chrom_order.push(rec.chrom().to_string());
+// Alignment of the current read, reused between records to avoid allocations
+struct AlignedRead {
+    start: i64, // 1-based inclusive span on reference
+    end: i64,
+    insert_size: i64, // fragment length if it's counted for the read, otherwise 0
+    weight: i64, // coverage multiplier, counts are scaled by MAPQ_WEIGHT_CAP with --mapq-weight to stay integer
+    strand: Strand, // Unknown unless strand matching is enabled
+    blocks: Vec<(i64, i64)>, // only filled in per-base depth mode
+    group: Option<usize>, // index of the read group with --split-by-rg, None for reads without known group
+    primer_spans: Vec<(i64, i64)>, // 1-based inclusive parts of the span inside primers, excluded from coverage
+    gaps: Vec<(i64, i64)>, // 1-based inclusive deletions longer than --del-gap, excluded from coverage
+    five_prime: Option<i64>, // end or start of the span by read strand, only set with --count-mode start5
+    start_key: Option<(i64, bool)>, // aligned start and reverse strand flag, only set with --count-mode unique-start
+    share: Option<(i64, i64)>, // read bases on any region and overlaps summed over regions, only set with --proportional-overlap
+}
+
+impl AlignedRead {
+    // Read bases inside the end-inclusive interval, without the ones overlapping primers or deletion gaps
+    fn coverage(&self, first: i64, last: i64) -> i64 {
+        let mut coverage = calculate_coverage(self.start..self.end, first..last);
+        for &(start, end) in &self.primer_spans {
+            coverage -= calculate_coverage(start..end, first..last);
+        }
+        for &(start, end) in &self.gaps {
+            let (gap_first, gap_last) = (start.max(first), end.min(last));
+            if gap_first > gap_last {
+                continue;
+            }
+            coverage -= gap_last - gap_first + 1;
+            // Bases of the gap inside primers are already subtracted
+            for &(start, end) in &self.primer_spans {
+                coverage += calculate_coverage(start..end, gap_first..gap_last);
+            }
+        }
+        coverage
     }
+}
 
-    for (chrom, chrom_nodes) in nodes {
-        bed_map.insert(chrom, COITree::new(&chrom_nodes));
+
+
+
+// 1-based inclusive span of the read as it is counted
+fn counted_span(record: &bam::Record, span_ops: SpanOps, include_softclip: bool, contig_length: i64) -> (i64, i64) {
+    let cigar = record.cigar();
+    let (start, end) = reference_span(&cigar, record.reference_start(), span_ops);
+    if !include_softclip {
+        return (start, end);
     }
-    eprintln!("Reading bed file finished");
+    let (leading, trailing) = soft_clips(&cigar);
+    ((start - leading).max(1), (end + trailing).min(contig_length))
+}
 
-    // Cleanup chrom ordering from duplicates
-    bed_chrom_order.dedup();
 
-    // Convert COITree to Querent that stores info about last region to optinize serach
-    let mut querents = FnvIndexMap::<String, COITreeSortedQuerent<RegionWithName, u32>>::default();
-    for (seqname, tree) in &bed_map {
-        querents.insert(seqname.clone(), COITreeSortedQuerent::new(tree));
+// Name and length of the reference contig of a bed contig, mitochondrial spellings are matched by same_contig
+fn reference_contig<'a>(contigs: &'a FxHashMap<String, u64>, chrom: &str) -> Option<(&'a str, u64)> {
+    contigs.get_key_value(chrom)
+        .or_else(|| contigs.iter().find(|(name, _)| same_contig(name, chrom)))
+        .map(|(name, &length)| (name.as_str(), length))
+}
+
+// Number of N bases of the reference in [start, end), None if the contig is missing from the reference
+fn reference_n_bases(reference: &faidx::Reader, contigs: &FxHashMap<String, u64>, chrom: &str, start: u64, end: u64) -> Option<i64> {
+    let (name, length) = reference_contig(contigs, chrom)?;
+    let end = end.min(length);
+    if end <= start {
+        return Some(0);
     }
+    let seq = reference.fetch_seq(name, start as usize, end as usize - 1).expect("Error reading reference sequence");
+    Some(seq.iter().filter(|&&base| base == b'N' || base == b'n').count() as i64)
+}
 
-    eprintln!("Starting processing bam file");
+fn reference_motif_sites(reference: &faidx::Reader, contigs: &FxHashMap<String, u64>, chrom: &str, start: u64, end: u64, motif: &[u8]) -> Vec<i64> {
+    let Some((name, length)) = reference_contig(contigs, chrom) else { return Vec::new() };
+    let end = end.min(length);
+    if end <= start {
+        return Vec::new();
+    }
+    let seq = reference.fetch_seq(name, start as usize, end as usize - 1).expect("Error reading reference sequence");
+    motif_sites(seq, motif)
+}
 
-    let mut bam = bam::Reader::from_path(cli.bam).unwrap();
-    bam.set_threads(bam_threads).expect("Error in setting number of threads for loading bam file");
+// Offsets of bases of motif occurrences starting at the ascending offsets, bases of overlapping occurrences once
+fn motif_bases(sites: &[i64], motif_length: i64) -> Vec<i64> {
+    let mut bases: Vec<i64> = Vec::new();
+    for &site in sites {
+        let from = bases.last().map_or(site, |&last| (last + 1).max(site));
+        bases.extend(from..site + motif_length);
+    }
+    bases
+}
+
+
+// Finds 0-based half-open runs with read span depth of at least --island-depth in a coordinate sorted bam.
+// Depth changes are kept only for reads that can still be overlapped by upcoming reads,
+// so memory doesn't depend on contig length.
+fn coverage_islands(cli: &CountArgs, flag_filter: &FlagFilter, span_ops: SpanOps, threads: usize) -> FnvIndexMap<String, Vec<(i64, i64)>> {
+    let mut bam = bam::Reader::from_path(&cli.bam).unwrap();
+    bam.set_threads(threads).expect("Error in setting number of threads for loading bam file");
+    if let Some((contig, length)) = oversized_contigs(bam.header()).first() {
+        fail(&format!("bam contig {contig} has {length} bases, coverage islands can't be found on contigs longer than {MAX_POSITION}"));
+    }
+    let contig_lengths: Vec<i64> = (0..bam.header().target_count()).map(|tid| bam.header().target_len(tid).unwrap_or(0) as i64).collect();
+    let mut islands = FnvIndexMap::<String, Vec<(i64, i64)>>::default();
+    let mut deltas = BTreeMap::<i64, i64>::new();
+    let mut depth = 0;
+    let mut island_start = None;
+    let mut current_contig = (-1, String::new());
+    let mut last_pos = 0;
+    // Soft clips move a read start back by at most its length, so depth stays open that far before the aligned start
+    let mut hold_back = 0;
+    let mut flushed = 0;
+
+    // Applies depth changes before the position, closing and opening islands on threshold crossings
+    let mut flush = |until: i64, deltas: &mut BTreeMap<i64, i64>, contig_islands: &mut Vec<(i64, i64)>| {
+        while let Some(entry) = deltas.first_entry() {
+            if *entry.key() >= until {
+                break;
+            }
+            let (pos, delta) = entry.remove_entry();
+            depth += delta;
+            match island_start {
+                None if depth >= cli.island_depth as i64 => island_start = Some(pos),
+                Some(start) if depth < cli.island_depth as i64 => {
+                    contig_islands.push((start, pos));
+                    island_start = None;
+                },
+                _ => (),
+            }
+        }
+    };
 
     for r in bam.rc_records() {
         let record = r.expect("Failure parsing Bam file");
-        if record.is_supplementary() { //skip supplementary aligments
+        if !flag_filter.passes(record.flags()) || record.tid() < 0 || record.tid() as usize >= contig_lengths.len() || record.mapq() < cli.min_mapq || !pairing_passes(cli, &record) || !fragment_length_passes(cli, &record) {
             continue;
         }
-        if record.tid() < 0 {
+        let pos = record.reference_start();
+        if record.tid() != current_contig.0 {
+            if current_contig.0 >= 0 {
+                flush(i64::MAX, &mut deltas, islands.entry(current_contig.1.clone()).or_default());
+            }
+            flushed = 0;
+            current_contig = (record.tid(), record.contig().to_string());
+            if islands.contains_key(&current_contig.1) {
+                fail("--regions-from-bam requires coordinate sorted bam");
+            }
+        } else if pos < last_pos {
+            fail("--regions-from-bam requires coordinate sorted bam");
+        }
+        last_pos = pos;
+        if cli.include_softclip_span {
+            hold_back = hold_back.max(record.seq_len() as i64).max(soft_clips(&record.cigar()).0);
+        }
+        // Upcoming reads start at pos - hold_back or later, so depth before it is final
+        flushed = flushed.max(pos - hold_back);
+        flush(flushed, &mut deltas, islands.entry(current_contig.1.clone()).or_default());
+        // Same span as counted later, so islands cover the reads exactly as they are counted. Only a read clipped
+        // further back than any earlier read is long can start in final depth, its island starts where depth is open
+        let (start, end) = counted_span(&record, span_ops, cli.include_softclip_span, contig_lengths[record.tid() as usize]);
+        *deltas.entry((start - 1).max(flushed)).or_insert(0) += 1;
+        *deltas.entry(end.max(flushed)).or_insert(0) -= 1;
+    }
+    if current_contig.0 >= 0 {
+        flush(i64::MAX, &mut deltas, islands.entry(current_contig.1).or_default());
+    }
+    islands.retain(|_, contig_islands| !contig_islands.is_empty());
+    islands
+}
+
+
+type StrandedSpan = (i64, i64, Strand);
+
+// Cluster of every span and merged spans of clusters with more than one amplicon, strand is kept if all amplicons share it
+fn collapsed_spans(spans: &[(i64, i64)], strands: &[Strand], min_overlap: i64) -> (Vec<usize>, FxHashMap<usize, StrandedSpan>) {
+    let clusters = collapse_overlapping(spans, min_overlap);
+    let mut merged: FxHashMap<usize, (i64, i64, Strand, usize)> = FxHashMap::default();
+    for ((&cluster, &(start, end)), &strand) in clusters.iter().zip(spans).zip(strands) {
+        let span = merged.entry(cluster).or_insert((start, end, strand, 0));
+        span.0 = span.0.min(start);
+        span.1 = span.1.max(end);
+        if span.2 != strand {
+            span.2 = Strand::Unknown;
+        }
+        span.3 += 1;
+    }
+    let merged = merged.into_iter().filter(|(_, span)| span.3 > 1).map(|(cluster, (start, end, strand, _))| (cluster, (start, end, strand))).collect();
+    (clusters, merged)
+}
+
+
+// Amplicon name to group from the first two columns of a tab separated file, empty and '#' lines are skipped
+fn read_group_map(path: &str) -> FxHashMap<String, String> {
+    let text = std::fs::read_to_string(path).expect("Error reading group map");
+    let mut groups = FxHashMap::default();
+    for (i, line) in text.lines().enumerate() {
+        if line.trim().is_empty() || line.starts_with('#') {
             continue;
         }
-        let start = record.reference_start() + 1;  //becuase start position will be included
-        let chrom = record.contig();
-        let end = start - 1
-            + record.cigar()
-                .iter()
-                .filter_map(|a| match a { 
-                   Cigar::Match(l) => Some(l),
-                   Cigar::Del(l) => Some(l),
-                   _ => None,
-                })
-                .sum::<u32>() as i64;
-
-        let querent_chrom = match querents.get_mut(chrom) {
-            Some(querent_chrom) => querent_chrom,
-            _ => continue,
-        };
-        querent_chrom.query((start-1) as i32, (end+1) as i32, |node| {update_node(start, end, node)}); // Runs update_node on
-        // each interval in tree that has intersection with query interval
+        match line.split('\t').collect::<Vec<_>>()[..] {
+            [name, group, ..] => { groups.insert(name.to_string(), group.to_string()); },
+            _ => fail(&format!("group map {path} line {} does not have name and group columns", i + 1)),
+        }
     }
+    groups
+}
 
-    eprintln!("Finished processing bam file");
 
-    eprintln!("Outputing result into stdout");
+// Position of every gene in the --gene-order file, the first line of a repeated gene counts
+fn read_gene_order(path: &str) -> FxHashMap<String, usize> {
+    let text = std::fs::read_to_string(path).expect("Error reading gene order");
+    let mut order = FxHashMap::default();
+    for line in text.lines() {
+        let gene = line.split('\t').next().unwrap().trim();
+        if gene.is_empty() || gene.starts_with('#') {
+            continue;
+        }
+        let position = order.len();
+        order.entry(gene.to_string()).or_insert(position);
+    }
+    order
+}
 
-    // Prepare the header
-    let mut output_string = String::from("Sample\tGene\tChr\tStart\tEnd\tTag\tLength\tMeanDepth\n");
 
-    for chrom in bed_chrom_order {
-        let chrom_tree = querents.get_mut(&chrom).unwrap(); //Safe to unwrap since it's guaranteed that we will have a hit
-        //let mut output = chrom_tree.iter()
-        let mut output = Vec::new();
-        chrom_tree.query(0, i32::MAX, |node| {output.push(OutputRegion{start:node.first as i64,
-                                                        end:node.last as i64,
-                                                        name:node.metadata.name.clone(),
-                                                        count:*node.metadata.count.borrow()})
-                                            });
+fn read_gene_lengths(path: &str) -> FxHashMap<String, i64> {
+    let text = std::fs::read_to_string(path).expect("Error reading gene lengths");
+    let mut lengths = FxHashMap::default();
+    for (i, line) in text.lines().enumerate() {
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match line.split('\t').collect::<Vec<_>>()[..] {
+            [gene, length, ..] => match length.trim().parse::<i64>() {
+                Ok(length) if length > 0 => { lengths.insert(gene.to_string(), length); },
+                _ => fail(&format!("gene lengths {path} line {} has invalid length '{length}'", i + 1)),
+            },
+            _ => fail(&format!("gene lengths {path} line {} does not have gene and length columns", i + 1)),
+        }
+    }
+    lengths
+}
 
-        output.sort();
-
-        let mut current_gene = "";
-        let mut total_length = 0;
-        let mut current_start = i64::MAX;
-        let mut current_end = 0;
-        let mut total_count = 0i64;
-        
-        for region in output.iter() {
-            if region.name != current_gene {
-                if !current_gene.is_empty() {
-                    // Calculate and write aggregated data for the previous gene
-                    let mean_depth = if total_length > 0 { total_count as f64 / total_length as f64} else { 0.0 };
-                    output_string += format!("{sample_name}\t{current_gene}\t{chrom}\t{current_start}\t{current_end}\tWhole-Gene\t{total_length}\t{mean_depth:.2}\n").as_str();
-                }
-                // Reset
-                current_gene = &region.name;
-                total_length = 0;
-                total_count = 0;
-                current_start = region.start;
-                current_end = 0;
-            }
-
-            // Process current region
-            let length = if mimic_perl_output {
-                region.end - region.start + 1 //Length in perl version of seq2c calculated +1
-            } else {
-                region.end - region.start
-            };
 
-            let count = region.count;
-            if region.end > current_end {
-                current_end = region.end;
+// Amplicons of one --group-map group, they can be on several chromosomes
+struct GroupSummary {
+    chrom: Option<String>, // None if amplicons are on more than one chromosome
+    start: i64,
+    end: i64,
+    length: i64,
+    count: f64, // weighted like gene counts
+}
+
+
+// IDs of @RG header lines in header order
+fn read_groups(header: &bam::HeaderView) -> Vec<String> {
+    bam::Header::from_template(header).to_hashmap().get("RG")
+        .map(|groups| groups.iter().filter_map(|group| group.get("ID").cloned()).collect())
+        .unwrap_or_default()
+}
+
+
+// Distinct SM values of @RG header lines in header order
+fn read_samples(header: &bam::HeaderView) -> Vec<String> {
+    let mut samples: Vec<String> = Vec::new();
+    for sample in bam::Header::from_template(header).to_hashmap().get("RG").into_iter().flatten().filter_map(|group| group.get("SM")) {
+        if !samples.contains(sample) {
+            samples.push(sample.clone());
+        }
+    }
+    samples
+}
+
+
+// Lengths of all sequences from the first two columns of a .fai file
+fn read_fai_lengths(path: &str) -> FxHashMap<String, u64> {
+    let text = std::fs::read_to_string(path).expect("Error reading reference index");
+    text.lines()
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            let mut fields = line.split('\t');
+            let name = fields.next().unwrap().to_string();
+            let length = fields.next().and_then(|length| length.parse().ok()).unwrap_or_else(|| fail(&format!("reference index {path} has no length for {name}")));
+            (name, length)
+        })
+        .collect()
+}
+
+
+// Lengths of all sequences in the fasta index
+fn reference_lengths(reference: &faidx::Reader) -> FxHashMap<String, u64> {
+    (0..reference.n_seqs() as i32)
+        .map(|i| reference.seq_name(i).expect("Error reading reference index"))
+        .map(|name| { let length = reference.fetch_seq_len(&name); (name, length) })
+        .collect()
+}
+
+
+fn htslib_version() -> String {
+    // hts_version returns a pointer to a static null terminated string
+    unsafe { std::ffi::CStr::from_ptr(rust_htslib::htslib::hts_version()) }.to_string_lossy().to_string()
+}
+
+
+// Writes a .tbi index next to a bgzipped bed
+fn tabix_index_bed(path: &str) -> Result<(), String> {
+    let c_path = std::ffi::CString::new(path).map_err(|_| format!("{path} can't be indexed, the path contains a NUL byte"))?;
+    let result = unsafe { rust_htslib::htslib::tbx_index_build(c_path.as_ptr(), 0, &rust_htslib::htslib::tbx_conf_bed) };
+    if result < 0 {
+        return Err(format!("can't build tabix index of {path}"));
+    }
+    Ok(())
+}
+
+
+// Comment lines describing how the output was produced
+fn provenance_header(cli: &CountArgs) -> String {
+    let prefix = &cli.comment_prefix;
+    let mut header = format!("{prefix}seq2c-rs version: {}\n", env!("CARGO_PKG_VERSION"));
+    header += &format!("{prefix}htslib version: {}\n", htslib_version());
+    header += &format!("{prefix}command: {}\n", std::env::args().collect::<Vec<_>>().join(" "));
+    let inputs = std::iter::once(("bam", &cli.bam))
+        .chain(cli.bed.iter().map(|path| ("bed", path)))
+        .chain(cli.refflat.iter().map(|path| ("refflat", path)))
+        .chain(cli.vcf.iter().map(|path| ("vcf", path)))
+        .chain(cli.reference.iter().map(|path| ("reference", path)));
+    for (kind, path) in inputs {
+        let modified = std::fs::metadata(path)
+            .and_then(|metadata| metadata.modified())
+            .ok()
+            .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+            .map_or(String::from("unknown"), |since_epoch| since_epoch.as_secs().to_string());
+        header += &format!("{prefix}input {kind}: {path} (modified {modified} seconds since unix epoch)\n");
+    }
+    header
+}
+
+
+// Tid of every bam contig to its index in the map. Contigs missing from the bam by their exact name
+// are matched by same_contig, so chrM of the bed is counted from MT of the bam
+fn contig_indices<V>(bam_chroms: &[String], map: &FnvIndexMap<String, V>) -> Vec<Option<usize>> {
+    bam_chroms.iter()
+        .map(|chrom| map.get_index_of(chrom).or_else(|| map.keys().position(|name| same_contig(name, chrom) && !bam_chroms.contains(name))))
+        .collect()
+}
+
+
+fn pairing_passes(cli: &CountArgs, record: &bam::Record) -> bool {
+    !cli.paired_only || (record.is_paired() && record.is_proper_pair())
+}
+
+
+// Template length filter of properly paired reads, other reads only fail it with --drop-unpaired
+fn fragment_length_passes(cli: &CountArgs, record: &bam::Record) -> bool {
+    if cli.min_fragment_length.is_none() && cli.max_fragment_length.is_none() {
+        return true;
+    }
+    if !record.is_proper_pair() {
+        return !cli.drop_unpaired;
+    }
+    let length = record.insert_size().unsigned_abs();
+    cli.min_fragment_length.is_none_or(|min| length >= min as u64) && cli.max_fragment_length.is_none_or(|max| length <= max as u64)
+}
+
+
+// FxHash of the file read in fixed size chunks, so the value only depends on the content
+fn file_checksum(path: &str) -> std::io::Result<u64> {
+    use std::hash::Hasher;
+    let mut file = File::open(path)?;
+    let mut hasher = rustc_hash::FxHasher::default();
+    let mut buffer = vec![0u8; 1 << 20];
+    loop {
+        let mut filled = 0;
+        while filled < buffer.len() {
+            match file.read(&mut buffer[filled..])? {
+                0 => break,
+                n => filled += n,
             }
-            output_string += format!("{sample_name}\t{}\t{chrom}\t{}\t{}\tAmplicon\t{}\t{:.2}\n", region.name, region.start, region.end, length, count as f64 /length as f64).as_str();
-            total_length += length;
-            total_count += count;
+        }
+        hasher.write(&buffer[..filled]);
+        if filled < buffer.len() {
+            return Ok(hasher.finish());
+        }
+    }
+}
+
+// Bed contents and options that change parsed regions, a saved index is only valid for the same hash.
+// The reference is identified by its path and size, hashing the whole fasta would cost more than parsing the bed
+fn panel_hash(cli: &CountArgs) -> u64 {
+    use std::hash::{Hash, Hasher};
+    let mut hasher = rustc_hash::FxHasher::default();
+    for path in cli.bed.iter().chain(&cli.refflat) {
+        file_checksum(path).unwrap_or_else(|e| fail(&format!("can't read bed file {path}: {e}"))).hash(&mut hasher);
+    }
+    (cli.bed_coords as u8, cli.empty_as_single_base, cli.weight_from_score, cli.exclude_n, env!("CARGO_PKG_VERSION")).hash(&mut hasher);
+    if cli.exclude_n {
+        if let Some(reference) = &cli.reference {
+            (reference, std::fs::metadata(reference).map(|metadata| metadata.len()).unwrap_or(0)).hash(&mut hasher);
+        }
+    }
+    hasher.finish()
+}
+
 
+// Sidecar json with everything needed to reproduce the run, stats are already rendered json values
+fn write_manifest(path: &str, cli: &CountArgs, matches: &clap::ArgMatches, started: std::time::SystemTime, stats: &[(&str, String)]) -> std::io::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    writeln!(writer, "{{")?;
+    writeln!(writer, "  \"seq2c_rs_version\": {},", json_string(env!("CARGO_PKG_VERSION")))?;
+    writeln!(writer, "  \"htslib_version\": {},", json_string(&htslib_version()))?;
+    writeln!(writer, "  \"command\": {},", json_string(&std::env::args().collect::<Vec<_>>().join(" ")))?;
+    let since_epoch = started.duration_since(std::time::UNIX_EPOCH).unwrap_or_default();
+    writeln!(writer, "  \"started\": {},", since_epoch.as_secs())?;
+    writeln!(writer, "  \"elapsed_seconds\": {:.3},", started.elapsed().unwrap_or_default().as_secs_f64())?;
+
+    let inputs: Vec<(&str, &String)> = std::iter::once(("bam", &cli.bam))
+        .chain(cli.bed.iter().map(|path| ("bed", path)))
+        .chain(cli.refflat.iter().map(|path| ("refflat", path)))
+        .chain(cli.vcf.iter().map(|path| ("vcf", path)))
+        .chain(cli.reference.iter().map(|path| ("reference", path)))
+        .chain(cli.group_map.iter().map(|path| ("group_map", path)))
+        .chain(cli.load_index.iter().map(|path| ("index", path)))
+        .chain(cli.primers.iter().map(|path| ("primers", path)))
+        .chain(cli.baseline.iter().map(|path| ("baseline", path)))
+        .chain(cli.gene_lengths.iter().map(|path| ("gene_lengths", path)))
+        .chain(cli.genes_file.iter().map(|path| ("genes", path)))
+        .collect();
+    writeln!(writer, "  \"inputs\": [")?;
+    for (i, (kind, input)) in inputs.iter().enumerate() {
+        let separator = if i + 1 < inputs.len() { "," } else { "" };
+        // Stdin was already consumed, it has no size or checksum
+        if *input == "-" {
+            writeln!(writer, "    {{\"kind\": \"{kind}\", \"path\": \"-\", \"size\": null, \"fxhash64\": null}}{separator}")?;
+            continue;
         }
+        let size = std::fs::metadata(input)?.len();
+        let checksum = file_checksum(input)?;
+        writeln!(writer, "    {{\"kind\": \"{kind}\", \"path\": {}, \"size\": {size}, \"fxhash64\": \"{checksum:016x}\"}}{separator}", json_string(input))?;
+    }
+    writeln!(writer, "  ],")?;
 
-        // at the end of the vector, write aggregated line for the last gene
-        let mean_depth = if total_length > 0 { total_count as f64 / total_length as f64 } else { 0.0 };
-        output_string += format!("{sample_name}\t{current_gene}\t{chrom}\t{current_start}\t{current_end}\tWhole-Gene\t{total_length}\t{mean_depth:.2}\n").as_str();
+    // Values as parsed by clap, defaults included, so the manifest doesn't depend on how options were spelled
+    let mut options = Vec::new();
+    for arg in CountArgs::augment_args(clap::Command::new("count")).get_arguments() {
+        let id = arg.get_id();
+        let Some(values) = matches.get_raw(id.as_str()) else { continue };
+        let values: Vec<String> = values.map(|value| json_string(&value.to_string_lossy())).collect();
+        // Repeatable options are always arrays
+        let value = if matches!(arg.get_action(), clap::ArgAction::Append) { format!("[{}]", values.join(", ")) } else { values.join(", ") };
+        options.push(format!("    {}: {value}", json_string(id.as_str())));
     }
-    print!("{}", output_string);
+    writeln!(writer, "  \"options\": {{\n{}\n  }},", options.join(",\n"))?;
 
-    eprintln!("Done");
+    let stats: Vec<String> = stats.iter().map(|(name, value)| format!("    \"{name}\": {value}")).collect();
+    writeln!(writer, "  \"stats\": {{\n{}\n  }}", stats.join(",\n"))?;
+    writeln!(writer, "}}")?;
+    writer.flush()
+}
+
+
+// Report paths paired with --format, stdout when a single format is written without --output
+fn report_paths(cli: &CountArgs) -> Vec<&str> {
+    if cli.output.is_empty() {
+        vec!["-"]
+    } else {
+        cli.output.iter().map(String::as_str).collect()
+    }
+}
+
+
+// All files written besides stdout, checked before the run so long runs don't clobber existing results
+fn output_paths(cli: &CountArgs) -> impl Iterator<Item = String> + '_ {
+    cli.output.iter().filter(|path| *path != "-")
+        .chain(cli.vcf_output.iter())
+        .chain(cli.callable.iter())
+        .chain(cli.amplicon_bed.iter())
+        .chain(cli.matrix.iter())
+        .chain(cli.split_by_rg.iter())
+        .chain(cli.manifest.iter())
+        .chain(cli.save_index.iter())
+        .chain(cli.target_bed.iter())
+        .cloned()
+        // tabix index built next to a bgzipped amplicon bed
+        .chain(cli.amplicon_bed.iter().filter(|path| path.ends_with(".gz")).map(|path| format!("{path}.tbi")))
+}
+
+
+// Selects the name field regions are aggregated by, falls back to the whole name if it has too few fields
+fn aggregation_key<'a>(name: &'a str, delimiter: Option<&str>, field: usize) -> Option<&'a str> {
+    match delimiter {
+        Some(delimiter) => name.split(delimiter).nth(field),
+        None => Some(name),
+    }
+}
+
+
+fn histogram_mean(histogram: &BTreeMap<i64, u64>) -> Option<f64> {
+    let total: u64 = histogram.values().sum();
+    (total > 0).then(|| histogram.iter().map(|(&value, &n)| value as f64 * n as f64).sum::<f64>() / total as f64)
+}
+
+fn histogram_median(histogram: &BTreeMap<i64, u64>) -> Option<f64> {
+    let total: u64 = histogram.values().sum();
+    if total == 0 {
+        return None;
+    }
+    // Values at the two middle ranks, the same for odd totals
+    let value_at = |rank: u64| {
+        let mut seen = 0;
+        histogram.iter().find(|(_, &n)| { seen += n; seen > rank }).map(|(&value, _)| value).unwrap()
+    };
+    Some((value_at((total - 1) / 2) + value_at(total / 2)) as f64 / 2.0)
+}
+
+// Mean depth over the depth reached by 80% of target bases, taken from amplicon mean depths weighted by length
+fn fold_80_penalty(amplicons: &mut [(f64, i64)]) -> Option<f64> {
+    let total: i64 = amplicons.iter().map(|&(_, length)| length.max(0)).sum();
+    if total == 0 {
+        return None;
+    }
+    let mean = amplicons.iter().map(|&(depth, length)| depth * length.max(0) as f64).sum::<f64>() / total as f64;
+    amplicons.sort_by(|a, b| b.0.total_cmp(&a.0));
+    let mut seen = 0;
+    let depth_80 = amplicons.iter().find(|&&(_, length)| { seen += length.max(0); seen as f64 >= 0.8 * total as f64 }).map(|&(depth, _)| depth).unwrap();
+    (depth_80 > 0.0).then(|| mean / depth_80)
+}
+
+
+// Unnamed regions and regions of the other strand with --strand-match don't count the read
+fn counts_read(read: &AlignedRead, metadata: &RegionWithName) -> bool {
+    metadata.name != "." && (metadata.strand == read.strand || metadata.strand.is_unknown() || read.strand.is_unknown())
+}
+
+
+// Regions with an overflowed count as name and span
+fn saturated_regions(querents: &mut FnvIndexMap<String, COITreeSortedQuerent<RegionWithName, u32>>) -> Vec<String> {
+    let mut saturated = Vec::new();
+    for (chrom, querent) in querents.iter_mut() {
+        querent.query(0, i32::MAX, |node| if *node.metadata.count.borrow() == i64::MAX {
+            saturated.push(format!("{} {chrom}:{}-{}", node.metadata.name, node.first, node.last));
+        });
+    }
+    saturated
+}
+
+
+fn update_node(read: &AlignedRead, interval: &IntervalNode<RegionWithName, u32>) -> bool {
+    let metadata = &interval.metadata;
+    if counts_read(read, metadata) {
+        let coverage = read.coverage(interval.first as i64, interval.last as i64);
+        // Weighted bases, split among overlapping regions with --proportional-overlap
+        let counted = match read.share {
+            Some((union, overlap_sum)) => overlap_share(coverage, union, overlap_sum, read.weight),
+            None => coverage.saturating_mul(read.weight),
+        };
+        let mut count = metadata.count.borrow_mut(); //Mutable borrow, but happens only in one thread, so it's fine
+        *count = add_coverage(*count, counted, 1);
+        if let Some(group) = read.group {
+            let mut group_counts = metadata.group_counts.borrow_mut();
+            if !group_counts.is_empty() {
+                group_counts[group] = add_coverage(group_counts[group], counted, 1);
+            }
+        }
+        // Central base of [first, last), 1-based like read positions
+        let midpoint = interval.first as i64 + (interval.last - interval.first) as i64 / 2 + 1;
+        if read.coverage(midpoint, midpoint) > 0 {
+            metadata.midpoint_count.set(add_coverage(metadata.midpoint_count.get(), 1, read.weight));
+        }
+        if read.five_prime.is_some_and(|five_prime| interval.first as i64 <= five_prime && five_prime <= interval.last as i64) {
+            metadata.start_count.set(metadata.start_count.get() + 1);
+        }
+        if let Some((start, reverse)) = read.start_key.filter(|_| coverage > 0) {
+            // Only pairs at the latest start are kept, a sorted bam never returns to an earlier one
+            let (last_start, strands) = metadata.last_start.get();
+            let strands = if start == last_start { strands } else { 0 };
+            let strand_bit = 1 << reverse as u8;
+            if strands & strand_bit == 0 {
+                metadata.unique_starts.set(metadata.unique_starts.get() + 1);
+            }
+            metadata.last_start.set((start, strands | strand_bit));
+        }
+        if read.insert_size > 0 {
+            *metadata.insert_sizes.borrow_mut().entry(read.insert_size).or_insert(0) += 1;
+        }
+        let mut depth = metadata.depth.borrow_mut();
+        if !depth.is_empty() {
+            let region_start = interval.first as i64;
+            for &(block_start, block_end) in &read.blocks {
+                // Parts of the block between primers, 1-based primer spans are [start - 1, end) 0-based
+                let mut from = block_start;
+                for &(start, end) in &read.primer_spans {
+                    if end <= from || start > block_end {
+                        continue;
+                    }
+                    depth.add(from - region_start, start - 1 - region_start);
+                    from = from.max(end);
+                }
+                depth.add(from - region_start, block_end - region_start);
+            }
+            // Later reads start at or after this one in a sorted bam
+            depth.settle(read.start - 1 - region_start);
+        }
+        return true;
+    }
+    false
+}
+
+
+
+fn main(){
+    let matches = Cli::command().get_matches();
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    match cli.command {
+        Some(Command::Count(count_args)) => count(*count_args, matches.subcommand_matches("count").unwrap()),
+        Some(Command::Merge(merge_args)) => merge(merge_args),
+        Some(Command::Stats(stats_args)) => stats(stats_args),
+        None => count(cli.count.expect("count options are required without a subcommand"), &matches),
+    }
+}
+
+
+fn merge(args: MergeArgs) {
+    if !args.force && std::path::Path::new(&args.output).exists() {
+        fail(&format!("output file {} already exists, use --force to overwrite it", args.output));
+    }
+    eprintln!("Merging {} reports into {}", args.reports.len(), args.output);
+    let mut writer = BufWriter::new(File::create(&args.output).expect("Error creating merged matrix output file"));
+    if let Err(e) = merge_reports(&mut writer, &args.reports, &args.amplicon_tag, &args.comment_prefix, args.tmpdir.as_deref()) {
+        drop(writer);
+        let _ = std::fs::remove_file(&args.output);
+        fail(&e);
+    }
+    writer.flush().expect("Error writing merged matrix");
+    eprintln!("Done");
+}
+
+
+fn stats(args: StatsArgs) {
+    let table = report_stats(&args.reports, &args.amplicon_tag, &args.comment_prefix).unwrap_or_else(|e| fail(&e));
+    let mut writer = BufWriter::new(std::io::stdout().lock());
+    table.write_tsv(&mut writer, "", 1).expect("Error writing stats");
+    writer.flush().expect("Error writing stats");
+}
+
+
+fn count(cli: CountArgs, matches: &clap::ArgMatches) {
+    let started = std::time::SystemTime::now();
+    let flag_filter = FlagFilter::new(&cli);
+    let base_depth = cli.callable.is_some() || cli.zero_bases || cli.window.is_some() || cli.depth_sd || cli.entropy || !cli.thresholds.is_empty() || cli.motif.is_some() || cli.covered_summary;
+    let strand_match = cli.strand_match;
+    let mut span_ops = cli.span_ops;
+    if let Some(del_covered) = cli.del_covered {
+        span_ops = span_ops.with(SpanOps::DEL, del_covered);
+    }
+    if let Some(skip_covered) = cli.skip_covered {
+        span_ops = span_ops.with(SpanOps::REF_SKIP, skip_covered);
+    }
+    let count_scale = if cli.mapq_weight { MAPQ_WEIGHT_CAP as f64 } else { 1.0 } * if cli.proportional_overlap { OVERLAP_SHARE_SCALE as f64 } else { 1.0 };
+    let mimic_perl_output = cli.mimic_perl_output;
+    let warnings = Warnings{ strict: cli.strict, raised: Cell::new(0) };
+    if cli.output.is_empty() && cli.format.len() > 1 {
+        fail("--output with a path for each of --format values is required to write several formats");
+    }
+    if !cli.output.is_empty() && cli.output.len() != cli.format.len() {
+        fail(&format!("{} --output paths given for {} --format values", cli.output.len(), cli.format.len()));
+    }
+    if cli.output.iter().filter(|path| *path == "-").count() > 1 {
+        fail("only one report can be written to stdout");
+    }
+    if let (Some(min), Some(max)) = (cli.min_fragment_length, cli.max_fragment_length) {
+        if min > max {
+            fail(&format!("--min-fragment-length {min} is above --max-fragment-length {max}"));
+        }
+    }
+    let stdin_beds = cli.bed.iter().filter(|path| *path == "-").count();
+    if stdin_beds > 1 {
+        fail("only one --bed can be read from stdin");
+    }
+    if stdin_beds > 0 && cli.bam == "-" {
+        fail("--bed - and --bam - can't both read stdin");
+    }
+    if stdin_beds > 0 && (cli.save_index.is_some() || cli.load_index.is_some()) {
+        fail("--save-index and --load-index checksum bed files, the bed can't be read from stdin with them");
+    }
+    if let Some(baseline) = cli.baseline.as_ref().filter(|path| is_baseline_bam(path)) {
+        let conflicts = baseline_bam_conflicts(&cli);
+        if !conflicts.is_empty() {
+            fail(&format!("baseline bam {baseline} is only counted with --exclude-flags, --min-mapq and span operations, not with {}, use a report of a run with the same options as --baseline instead", conflicts.join(", ")));
+        }
+    }
+    if !(0.0..=1.0).contains(&cli.covered_breadth) {
+        fail("--covered-breadth has to be between 0 and 1");
+    }
+    if !cli.thresholds.windows(2).all(|pair| pair[0] < pair[1]) {
+        fail("--thresholds have to be sorted in ascending order without repeats");
+    }
+    if cli.pseudocount <= 0.0 {
+        fail("--pseudocount has to be positive");
+    }
+    if cli.append && (cli.output.is_empty() || cli.output.iter().any(|path| path == "-") || cli.format.contains(&OutputFormat::Json)) {
+        fail("--append needs --output files in tsv format");
+    }
+    if !cli.force {
+        // Appended reports are expected to exist, other outputs are still protected
+        for path in output_paths(&cli).filter(|path| !(cli.append && cli.output.contains(path))) {
+            if std::path::Path::new(&path).exists() {
+                fail(&format!("output file {path} already exists, use --force to overwrite it"));
+            }
+        }
+    }
+    let gene_min_depth = cli.gene_min_depth;
+    eprintln!("Started");
+
+    let bam_threads = if cli.threads == 0 {
+            available_parallelism().expect("Wasn't able to automatically reconize number of threads, please set it by setting --threads argument manually").get()
+        } else {
+            cli.threads
+    };
+    eprintln!("Using {bam_threads} threads for reading bam file");
+
+    let mut nodes: FxHashMap<String, Vec<Interval<RegionWithName>>> = FxHashMap::default();
+    let mut bed_map: FxHashMap<String, COITree<RegionWithName, u32>> = FxHashMap::default();
+
+    let mut bed_chrom_order: Vec<String> = Vec::new();
+    // A .fai index alone is enough to validate contig lengths, sequence is only needed for --exclude-n
+    let fai_only = cli.reference.as_ref().is_some_and(|path| path.ends_with(".fai"));
+    if fai_only && cli.exclude_n {
+        fail("--exclude-n needs the reference fasta, --reference is a .fai index");
+    }
+    if fai_only && cli.motif.is_some() {
+        fail("--motif needs the reference fasta, --reference is a .fai index");
+    }
+    let motif = cli.motif.as_ref().map(|motif| {
+        if motif.is_empty() || !motif.bytes().all(|base| b"ACGTacgt".contains(&base)) {
+            fail(&format!("--motif {motif} has to be a sequence of A, C, G and T bases"));
+        }
+        motif.to_ascii_uppercase().into_bytes()
+    });
+    let reference = cli.reference.as_ref().filter(|_| !fai_only).map(|path| faidx::Reader::from_path(path).expect("Error opening reference fasta, it should be indexed with samtools faidx"));
+    let reference_contigs = match (&reference, &cli.reference) {
+        (Some(reference), _) => reference_lengths(reference),
+        (None, Some(fai_path)) => read_fai_lengths(fai_path),
+        (None, None) => FxHashMap::default(),
+    };
+    if cli.regions_from_bam {
+        // Islands are reported as amplicons named by their bed coordinates, each of them is its own gene
+        eprintln!("Finding coverage islands with depth of at least {}", cli.island_depth);
+        for (chrom, islands) in coverage_islands(&cli, &flag_filter, span_ops, bam_threads) {
+            let chrom_nodes = islands.iter().map(|&(start, end)| {
+                let mut region = RegionWithName::new(format!("{chrom}:{start}-{end}"), Strand::Unknown, if base_depth { (end - start) as usize } else { 0 });
+                if let (true, Some(reference)) = (cli.exclude_n, &reference) {
+                    region.n_bases = reference_n_bases(reference, &reference_contigs, &chrom, start as u64, end as u64).unwrap_or(0);
+                }
+                Interval::new(start as i32, end as i32, region)
+            }).collect();
+            bed_chrom_order.push(chrom.clone());
+            nodes.insert(chrom, chrom_nodes);
+        }
+        eprintln!("Found {} coverage islands", nodes.values().map(Vec::len).sum::<usize>());
+    }
+
+    // Regions of a saved index were already validated when it was written, bed files are only hashed
+    let current_panel_hash = (cli.save_index.is_some() || cli.load_index.is_some()).then(|| panel_hash(&cli));
+    if let Some(index_path) = &cli.load_index {
+        eprintln!("Loading bed regions from index {index_path}");
+        let index = load_index(index_path).unwrap_or_else(|e| fail(&e));
+        if Some(index.panel_hash) != current_panel_hash {
+            fail(&format!("index {index_path} was saved for other bed files or options, save it again with --save-index"));
+        }
+        for region in index.regions {
+            let chrom = &index.chromosomes[region.chrom];
+            let depth_length = if base_depth { (region.last - region.first) as usize } else { 0 };
+            let mut metadata = RegionWithName::new(region.name, region.strand, depth_length);
+            (metadata.score_weight, metadata.n_bases, metadata.input_index) = (region.score_weight, region.n_bases, region.input_index);
+            nodes.entry(chrom.clone()).or_default().push(Interval::new(region.first, region.last, metadata));
+        }
+        bed_chrom_order = index.chromosomes;
+    } else {
+        eprintln!("Reading bed file");
+    }
+    let bed_paths: &[String] = if cli.load_index.is_some() { &[] } else { &cli.bed };
+    let refflat_path = cli.refflat.as_ref().filter(|_| cli.load_index.is_none());
+    let mut missing_reference_contigs = FxHashSet::default();
+    let mut out_of_bounds = Vec::new();
+    let mut bed_records = 0;
+    let mut unsorted_bed = Vec::new();
+    let mut unnamed_records = 0;
+    let mut unweighted_records = 0;
+    let mut seen_records = FxHashSet::default(); // chromosome, coordinates and name of every record
+    let mut duplicate_records = 0;
+    // Records of all bed files are merged into one panel, regions with the same name are aggregated into one gene
+    // regardless of the file they come from, chromosomes are ordered by their first appearance
+    let (start_shift, bed_coords_name) = match cli.bed_coords {
+        BedCoords::ZeroHalfOpen => (0, "0-based half-open"),
+        BedCoords::OneInclusive => (1, "1-based inclusive"),
+    };
+    for bed_path in bed_paths.iter().chain(refflat_path) {
+        let mut file_chroms = FxHashSet::default();
+        let mut last_chrom = String::new();
+        let mut last_start = 0;
+        // A refFlat table is converted to sorted bed records of merged exons up front
+        let (mut reader, refflat_records) = match refflat_path {
+            Some(path) if path == bed_path => (None, read_refflat(path, &warnings)),
+            _ => (Some(open_bed(bed_path)), Vec::new()),
+        };
+        let file_shift = if reader.is_some() { start_shift } else { 0 };
+        let records = reader.as_mut().map(|reader| reader.records()).into_iter().flatten().chain(refflat_records.into_iter().map(Ok));
+        for record in records {
+            // Malformed lines are skipped, so one broken record doesn't abort the whole panel
+            let rec = match record {
+                Ok(rec) => rec,
+                Err(e) => {
+                    let line = e.position().map_or(String::from("unknown"), |position| position.line().to_string());
+                    warnings.warn(&format!("Malformed BED record in {bed_path} at line {line} is {}: {e}", warnings.skipped()));
+                    continue;
+                },
+            };
+            // 1-based inclusive records are converted to standard bed coordinates on load
+            let (start, end) = match (rec.start().checked_sub(file_shift), rec.end()) {
+                (Some(start), end) if end > start => (start, end),
+                // Zero length record, usually a point feature like an insertion site
+                (Some(start), end) if end == start && cli.empty_as_single_base => (start, end + 1),
+                (Some(start), end) if end == start => {
+                    warnings.warn(&format!("BED record {}:{}-{} in {bed_path} is empty, it is {}, use --empty-as-single-base to count it as the base after start", rec.chrom(), rec.start(), rec.end(), warnings.skipped()));
+                    continue;
+                },
+                _ => {
+                    warnings.warn(&format!("BED record {}:{}-{} in {bed_path} is not a valid {} interval, it is {}", rec.chrom(), rec.start(), rec.end(), bed_coords_name, warnings.skipped()));
+                    continue;
+                },
+            };
+            // Interval trees would silently truncate larger positions
+            if end > MAX_POSITION as u64 {
+                fail(&format!("BED record {}:{}-{} in {bed_path} ends beyond position {MAX_POSITION}, contigs longer than that are not supported", rec.chrom(), rec.start(), rec.end()));
+            }
+            if last_chrom != rec.chrom() {
+                if !file_chroms.insert(rec.chrom().to_string()) && !unsorted_bed.contains(bed_path) {
+                    unsorted_bed.push(bed_path.clone()); // chromosome block appears for the second time
+                }
+                if !nodes.contains_key(rec.chrom()) {
+                    bed_chrom_order.push(rec.chrom().to_string());
+                }
+                last_chrom = rec.chrom().to_string();
+            } else if start < last_start && !unsorted_bed.contains(bed_path) {
+                unsorted_bed.push(bed_path.clone());
+            }
+            last_start = start;
+            // Identical lines, e.g. of overlapping panels concatenated together, would be counted twice in their gene
+            if !seen_records.insert((rec.chrom().to_string(), start, end, rec.name().map(str::to_string))) {
+                duplicate_records += 1;
+                continue;
+            }
+            let name = match rec.name() {
+                Some(name) => name.to_string(),
+                None => {
+                    unnamed_records += 1;
+                    if warnings.strict {
+                        warnings.warn(&format!("BED record {}:{}-{} does not define name", rec.chrom(), start, end));
+                    }
+                    String::from(".")
+                }
+            };
+            let mut region = RegionWithName::new(name, rec.strand().unwrap_or(Strand::Unknown), if base_depth { (end - start) as usize } else { 0 });
+            region.input_index = bed_records;
+            bed_records += 1;
+            if cli.weight_from_score {
+                match rec.score().and_then(|score| score.parse::<f64>().ok()).filter(|score| score.is_finite() && *score > 0.0) {
+                    Some(score) => region.score_weight = score,
+                    None => unweighted_records += 1,
+                }
+            }
+            if cli.reference.is_some() {
+                match reference_contig(&reference_contigs, rec.chrom()).map(|(_, length)| length) {
+                    Some(contig_length) if end > contig_length => out_of_bounds.push(format!("{}:{start}-{end} (contig length {contig_length})", rec.chrom())),
+                    Some(_) => (),
+                    None => {
+                        if !missing_reference_contigs.contains(rec.chrom()) {
+                            warnings.warn(&format!("BED contig {} is not present in reference", rec.chrom()));
+                            missing_reference_contigs.insert(rec.chrom().to_string());
+                        }
+                    },
+                }
+            }
+            if let (true, Some(reference)) = (cli.exclude_n, &reference) {
+                region.n_bases = reference_n_bases(reference, &reference_contigs, rec.chrom(), start, end).unwrap_or(0);
+            }
+            let node_vec = nodes.entry(rec.chrom().to_string()).or_default();
+            node_vec.push(Interval::new(start as i32, end as i32, region));
+        }
+    }
+    if !out_of_bounds.is_empty() {
+        // Usually a bed of another reference build, e.g. hg19 bed with hg38 reference
+        let listed = out_of_bounds.iter().take(10).cloned().collect::<Vec<_>>().join(", ");
+        let more = if out_of_bounds.len() > 10 { format!(" and {} more", out_of_bounds.len() - 10) } else { String::new() };
+        warnings.warn(&format!("{} BED regions end after the end of their reference contig, check that bed and reference use the same build: {listed}{more}", out_of_bounds.len()));
+    }
+    for bed_path in unsorted_bed {
+        warnings.warn(&format!("BED file {bed_path} is not sorted by chromosome and start position"));
+    }
+    if duplicate_records > 0 {
+        warnings.warn(&format!("{duplicate_records} BED records have the same chromosome, start, end and name as an earlier record, the copies are {}", warnings.skipped()));
+    }
+    if unweighted_records > 0 {
+        eprintln!("{unweighted_records} BED records have no positive score, their depth is not weighted");
+    }
+    if unnamed_records > 0 {
+        warnings.warn(&format!("{unnamed_records} BED records do not define name, they are reported as unnamed regions '.' and not counted"));
+    }
+    // A dry run only validates, none of the outputs is written
+    if let (Some(index_path), Some(panel_hash), false) = (&cli.save_index, current_panel_hash, cli.dry_run) {
+        let regions = bed_chrom_order.iter().enumerate()
+            .flat_map(|(chrom, name)| nodes[name].iter().map(move |node| IndexedRegion {
+                chrom,
+                first: node.first,
+                last: node.last,
+                name: node.metadata.name.clone(),
+                strand: node.metadata.strand,
+                score_weight: node.metadata.score_weight,
+                n_bases: node.metadata.n_bases,
+                input_index: node.metadata.input_index,
+            }))
+            .collect();
+        save_index(index_path, &PanelIndex{ panel_hash, chromosomes: bed_chrom_order.clone(), regions }).expect("Error writing panel index");
+        eprintln!("Saved bed regions to index {index_path}");
+    }
+
+    let mut selected_genes: FxHashSet<String> = cli.genes.iter().cloned().collect();
+    if let Some(path) = &cli.genes_file {
+        selected_genes.extend(read_gene_order(path).into_keys());
+    }
+    if !selected_genes.is_empty() {
+        // The whole panel is validated and saved to the index, only counting and reporting are restricted
+        let mut found_genes = FxHashSet::default();
+        for chrom_nodes in nodes.values_mut() {
+            chrom_nodes.retain(|node| {
+                let gene = aggregation_key(&node.metadata.name, cli.name_delimiter.as_deref(), cli.aggregate_field).unwrap_or(&node.metadata.name);
+                let selected = selected_genes.contains(gene);
+                if selected {
+                    found_genes.insert(gene.to_string());
+                }
+                selected
+            });
+        }
+        let mut missing_genes: Vec<&String> = selected_genes.iter().filter(|gene| !found_genes.contains(*gene)).collect();
+        if !missing_genes.is_empty() {
+            missing_genes.sort_unstable();
+            fail(&format!("{} selected genes are not in the bed: {}", missing_genes.len(), missing_genes.iter().map(|gene| gene.as_str()).collect::<Vec<_>>().join(", ")));
+        }
+        nodes.retain(|_, chrom_nodes| !chrom_nodes.is_empty());
+        bed_chrom_order.retain(|chrom| nodes.contains_key(chrom));
+    }
+
+    if let (Some(motif), Some(reference)) = (&motif, &reference) {
+        // Only occurrences fully inside a region count, searched after selection so skipped genes cost nothing
+        for (chrom, chrom_nodes) in nodes.iter_mut() {
+            for node in chrom_nodes.iter_mut() {
+                node.metadata.motif_sites = reference_motif_sites(reference, &reference_contigs, chrom, node.first as u64, node.last as u64, motif);
+            }
+        }
+    }
+
+    let mut panel_amplicons = 0;
+    let mut panel_genes = FxHashSet::default();
+    let mut panel_bases = 0;
+    for chrom_nodes in nodes.values() {
+        let mut targets: Vec<(i64, i64)> = chrom_nodes.iter()
+            .filter(|node| node.metadata.name != ".")
+            .map(|node| (node.first as i64, node.last as i64))
+            .collect();
+        panel_amplicons += targets.len();
+        panel_genes.extend(chrom_nodes.iter()
+            .filter(|node| node.metadata.name != ".")
+            .map(|node| aggregation_key(&node.metadata.name, cli.name_delimiter.as_deref(), cli.aggregate_field).unwrap_or(&node.metadata.name).to_string()));
+        panel_bases += merge_intervals(&mut targets).iter().map(|(start, end)| end - start).sum::<i64>();
+    }
+
+    // Reads on bases shared by regions of different genes are counted for both genes
+    let mut overlapping_genes: Vec<String> = Vec::new();
+    let mut seen_pairs = FxHashSet::default();
+    for chrom in &bed_chrom_order {
+        let mut targets: Vec<(i64, i64, &str)> = nodes[chrom].iter()
+            .filter(|node| node.metadata.name != ".")
+            .map(|node| (node.first as i64, node.last as i64, aggregation_key(&node.metadata.name, cli.name_delimiter.as_deref(), cli.aggregate_field).unwrap_or(&node.metadata.name)))
+            .collect();
+        targets.sort_unstable();
+        let mut active: Vec<(i64, i64, &str)> = Vec::new();
+        for (start, end, gene) in targets {
+            active.retain(|&(_, active_end, _)| active_end > start);
+            for &(active_start, active_end, active_gene) in &active {
+                // Bed intervals are half-open, calculate_coverage takes end-inclusive ones
+                if active_gene != gene && calculate_coverage(start..end - 1, active_start..active_end - 1) > 0 {
+                    let pair = if active_gene < gene { (active_gene, gene) } else { (gene, active_gene) };
+                    if seen_pairs.insert((chrom.as_str(), pair)) {
+                        overlapping_genes.push(format!("{} and {} on {chrom}", pair.0, pair.1));
+                    }
+                }
+            }
+            active.push((start, end, gene));
+        }
+    }
+    if !overlapping_genes.is_empty() {
+        let listed = overlapping_genes.iter().take(10).cloned().collect::<Vec<_>>().join(", ");
+        let more = if overlapping_genes.len() > 10 { format!(" and {} more", overlapping_genes.len() - 10) } else { String::new() };
+        warnings.warn(&format!("{} pairs of genes have overlapping regions, reads on shared bases are counted for both genes: {listed}{more}", overlapping_genes.len()));
+    }
+
+    // Same union the merged target bases are counted from, written with the other outputs once counting succeeded
+    let target_bed = cli.target_bed.as_ref().filter(|_| !cli.dry_run).map(|path| {
+        let mut text = String::new();
+        for chrom in &bed_chrom_order {
+            let mut targets: Vec<(i64, i64, &str)> = nodes[chrom].iter()
+                .filter(|node| node.metadata.name != ".")
+                .map(|node| (node.first as i64, node.last as i64, aggregation_key(&node.metadata.name, cli.name_delimiter.as_deref(), cli.aggregate_field).unwrap_or(&node.metadata.name)))
+                .collect();
+            targets.sort_unstable();
+            let mut merged: Vec<(i64, i64, &str)> = Vec::new();
+            for (start, end, gene) in targets {
+                match merged.last_mut() {
+                    Some(last) if start <= last.1 => {
+                        last.1 = last.1.max(end);
+                        if last.2 != gene {
+                            last.2 = ".";
+                        }
+                    },
+                    _ => merged.push((start, end, gene)),
+                }
+            }
+            for (start, end, gene) in merged {
+                text.push_str(&format!("{chrom}\t{start}\t{end}\t{gene}\n"));
+            }
+        }
+        (path, text)
+    });
+
+    if cli.verbose > 0 {
+        for chrom in &bed_chrom_order {
+            let named: Vec<&str> = nodes[chrom].iter().map(|node| node.metadata.name.as_str()).filter(|name| *name != ".").collect();
+            let genes: FxHashSet<&str> = named.iter().map(|name| aggregation_key(name, cli.name_delimiter.as_deref(), cli.aggregate_field).unwrap_or(name)).collect();
+            eprintln!("  {chrom}: {} amplicons, {} genes", named.len(), genes.len());
+        }
+    }
+
+    let groups = if cli.split_by_rg.is_some() {
+        read_groups(bam::Reader::from_path(&cli.bam).unwrap().header())
+    } else {
+        Vec::new()
+    };
+    if cli.split_by_rg.is_some() {
+        if groups.is_empty() {
+            warnings.warn("bam header has no @RG lines, read group matrix has no depth columns");
+        }
+        for node in nodes.values_mut().flatten() {
+            node.metadata.group_counts = RefCell::new(vec![0; groups.len()]);
+        }
+    }
+    let group_index: FxHashMap<&[u8], usize> = groups.iter().enumerate().map(|(i, group)| (group.as_bytes(), i)).collect();
+
+    // Flanks and collapsed amplicons are counted in separate trees like variant sites,
+    // amplicons find them by their coordinates and strand
+    let mut extra_map: FxHashMap<String, COITree<RegionWithName, u32>> = FxHashMap::default();
+    let mut clipped_flanks = 0;
+    if cli.flank.is_some() || cli.collapse_amplicons.is_some() {
+        // Flanks are clipped to contig lengths of the bam header, contigs missing from it aren't clipped at the end
+        let header_lengths: Vec<(String, i64)> = {
+            let reader = bam::Reader::from_path(&cli.bam).unwrap();
+            (0..reader.header().target_count())
+                .map(|tid| (String::from_utf8_lossy(reader.header().tid2name(tid)).to_string(), reader.header().target_len(tid).unwrap_or(0) as i64))
+                .collect()
+        };
+        for (chrom, chrom_nodes) in &nodes {
+            let mut extra_nodes = Vec::new();
+            let named_nodes = chrom_nodes.iter().filter(|node| node.metadata.name != ".");
+            if let Some(flank) = cli.flank {
+                let contig_length = header_lengths.iter().find(|(name, _)| name == chrom)
+                    .or_else(|| header_lengths.iter().find(|(name, _)| same_contig(name, chrom)))
+                    .map_or(i64::MAX, |&(_, length)| length);
+                let flank = flank as i64;
+                for node in named_nodes.clone() {
+                    let (first, last) = (node.first as i64, node.last as i64);
+                    for (flank_first, flank_last) in [(first - flank, first - 1), (last + 1, last + flank)] {
+                        let clipped = clip_to_contig(flank_first, flank_last, contig_length);
+                        if clipped != Some((flank_first, flank_last)) {
+                            clipped_flanks += 1;
+                        }
+                        if let Some((flank_first, flank_last)) = clipped {
+                            extra_nodes.push(Interval::new(flank_first as i32, flank_last as i32, RegionWithName::new(String::from("flank"), node.metadata.strand, 0)));
+                        }
+                    }
+                }
+            }
+            if let Some(min_overlap) = cli.collapse_amplicons {
+                let mut genes = FnvIndexMap::<&str, Vec<&Interval<RegionWithName>>>::default();
+                for node in named_nodes {
+                    genes.entry(aggregation_key(&node.metadata.name, cli.name_delimiter.as_deref(), cli.aggregate_field).unwrap_or(&node.metadata.name)).or_default().push(node);
+                }
+                for (gene, gene_nodes) in genes {
+                    let spans: Vec<(i64, i64)> = gene_nodes.iter().map(|node| (node.first as i64, node.last as i64)).collect();
+                    let (_, merged) = collapsed_spans(&spans, &gene_nodes.iter().map(|node| node.metadata.strand).collect::<Vec<_>>(), min_overlap);
+                    for (start, end, strand) in merged.into_values() {
+                        extra_nodes.push(Interval::new(start as i32, end as i32, RegionWithName::new(gene.to_string(), strand, 0)));
+                    }
+                }
+            }
+            extra_map.insert(chrom.clone(), COITree::new(&extra_nodes));
+        }
+    }
+    if clipped_flanks > 0 && warnings.strict {
+        warnings.warn(&format!("{clipped_flanks} flanks extend past the start or end of their contig"));
+    }
+
+    // Regions counted in a baseline bam, the bam itself is only read after the dry run
+    let baseline_regions: Vec<Region> = match cli.baseline {
+        Some(_) => bed_chrom_order.iter()
+            .flat_map(|chrom| nodes[chrom].iter().filter(|node| node.metadata.name != ".").map(move |node| (chrom, node)))
+            .map(|(chrom, node)| Region{ chrom: chrom.clone(), start: node.first as u64, end: node.last as u64, name: node.metadata.name.clone() })
+            .collect(),
+        None => Vec::new(),
+    };
+
+    for (chrom, chrom_nodes) in nodes {
+        bed_map.insert(chrom, COITree::new(&chrom_nodes));
+    }
+    eprintln!("Reading bed file finished");
+
+    // Convert COITree to Querent that stores info about last region to optinize serach
+    let mut querents = FnvIndexMap::<String, COITreeSortedQuerent<RegionWithName, u32>>::default();
+    for (seqname, tree) in &bed_map {
+        querents.insert(seqname.clone(), COITreeSortedQuerent::new(tree));
+    }
+
+    // Variant sites are kept in separate trees, so they don't affect gene output
+    let mut site_map: FxHashMap<String, COITree<RegionWithName, u32>> = FxHashMap::default();
+    let mut site_chrom_order = Vec::new();
+    if let Some(vcf_path) = &cli.vcf {
+        eprintln!("Reading vcf file");
+        for (chrom, positions) in read_variant_sites(vcf_path) {
+            let site_nodes: Vec<Interval<RegionWithName>> = positions.iter()
+                .map(|&pos| Interval::new(pos as i32, pos as i32, RegionWithName::new(String::from("site"), Strand::Unknown, 0)))
+                .collect();
+            site_map.insert(chrom.clone(), COITree::new(&site_nodes));
+            site_chrom_order.push(chrom);
+        }
+        eprintln!("Reading vcf file finished");
+    }
+    let mut site_querents = FnvIndexMap::<String, COITreeSortedQuerent<RegionWithName, u32>>::default();
+    for (seqname, tree) in &site_map {
+        site_querents.insert(seqname.clone(), COITreeSortedQuerent::new(tree));
+    }
+    let mut extra_querents = FnvIndexMap::<String, COITreeSortedQuerent<RegionWithName, u32>>::default();
+    for (seqname, tree) in &extra_map {
+        extra_querents.insert(seqname.clone(), COITreeSortedQuerent::new(tree));
+    }
+
+    let mut bam = bam::Reader::from_path(&cli.bam).unwrap();
+    bam.set_threads(bam_threads).expect("Error in setting number of threads for loading bam file");
+    let sample_name = if cli.sample_from_bam {
+        let samples = read_samples(bam.header());
+        let sample_name = match (samples.len(), &cli.sample_name) {
+            (0, Some(sample_name)) => sample_name.clone(),
+            (0, None) => fail("bam header has no @RG line with SM, --sample-name is required"),
+            (1, _) => samples[0].clone(),
+            // Read groups of the samples are reported separately in the read group matrix
+            (_, Some(sample_name)) if cli.split_by_rg.is_some() => sample_name.clone(),
+            _ if cli.split_by_rg.is_some() => samples.join(","),
+            _ => fail(&format!("bam header has @RG lines of {} samples ({}), use --split-by-rg or --sample-name instead of --sample-from-bam", samples.len(), samples.join(", "))),
+        };
+        eprintln!("Sample name: {sample_name}");
+        sample_name
+    } else {
+        cli.sample_name.clone().unwrap()
+    };
+
+    let bam_chroms: Vec<String> = bam.header().target_names().iter().map(|name| String::from_utf8_lossy(name).to_string()).collect();
+    // Reads beyond i32 positions would be matched to truncated coordinates, only contigs without targets can be that long
+    for (contig, length) in oversized_contigs(bam.header()) {
+        if bed_chrom_order.iter().chain(&site_chrom_order).any(|chrom| same_contig(&contig, chrom)) {
+            fail(&format!("bam contig {contig} has {length} bases, contigs with targets can't be longer than {MAX_POSITION}"));
+        }
+    }
+    for chrom in &bed_chrom_order {
+        if !bam_chroms.iter().any(|name| same_contig(name, chrom)) {
+            warnings.warn(&format!("BED contig {chrom} is not present in bam header"));
+        }
+    }
+    for chrom in &site_chrom_order {
+        if !bam_chroms.iter().any(|name| same_contig(name, chrom)) {
+            warnings.warn(&format!("VCF contig {chrom} is not present in bam header"));
+        }
+    }
+    // Same check as with --reference, contig lengths of the bam header come for free
+    let mut beyond_header = Vec::new();
+    for chrom in &bed_chrom_order {
+        let Some(tid) = bam_chroms.iter().position(|name| name == chrom).or_else(|| bam_chroms.iter().position(|name| same_contig(name, chrom))) else { continue };
+        let contig_length = bam.header().target_len(tid as u32).unwrap_or(0) as i64;
+        if contig_length >= MAX_POSITION {
+            continue;
+        }
+        bed_map[chrom].query(contig_length as i32 + 1, i32::MAX, |node| beyond_header.push((chrom, node.first, node.last, contig_length)));
+    }
+    if !beyond_header.is_empty() {
+        beyond_header.sort_unstable();
+        let listed = beyond_header.iter().take(10).map(|(chrom, start, end, length)| format!("{chrom}:{start}-{end} (contig length {length})")).collect::<Vec<_>>().join(", ");
+        let more = if beyond_header.len() > 10 { format!(" and {} more", beyond_header.len() - 10) } else { String::new() };
+        warnings.warn(&format!("{} BED regions end after the end of their contig in the bam header, check that bed and bam use the same build: {listed}{more}", beyond_header.len()));
+    }
+
+    if cli.dry_run {
+        eprintln!("Dry run:");
+        eprintln!("  Alignment format: {}", detect_alignment_format(&cli.bam));
+        eprintln!("  Chromosomes: {}", bed_chrom_order.len());
+        eprintln!("  Regions: {}", bed_map.values().map(|tree| tree.len()).sum::<usize>());
+        if warnings.raised.get() > 0 {
+            eprintln!("Validation failed with {} warnings", warnings.raised.get());
+            std::process::exit(1);
+        }
+        eprintln!("Validation passed");
+        return;
+    }
+
+    let baseline = cli.baseline.as_ref().map(|path| {
+        if is_baseline_bam(path) {
+            // Counted by the library with the main flag, MAPQ and span filters, runs with other read options were rejected
+            eprintln!("Counting baseline bam file {path}");
+            let calculator = CoverageCalculator::new(baseline_regions).span_ops(span_ops).exclude_flags(flag_filter.exclude).min_mapq(cli.min_mapq);
+            let mut reader = bam::Reader::from_path(path).expect("Error opening baseline bam file");
+            reader.set_threads(bam_threads).expect("Error in setting number of threads for loading baseline bam file");
+            let genes = calculator.coverage(reader).unwrap_or_else(|e| fail(&format!("baseline {path}: {e}")));
+            Baseline::Counts(genes.into_iter()
+                .flat_map(|gene| gene.amplicons.into_iter().map(move |amplicon| ((gene.chrom.clone(), amplicon.start, amplicon.end), amplicon.count as f64)))
+                .collect())
+        } else {
+            Baseline::Depths(read_baseline_depths(path, &cli.amplicon_tag, &cli.comment_prefix).unwrap_or_else(|e| fail(&e)).into_iter()
+                .map(|((chrom, start, end), depth)| ((chrom, start - cli.coord_base, end), depth))
+                .collect())
+        }
+    });
+
+    eprintln!("Starting processing bam file");
+
+    let contig_lengths: Vec<i64> = (0..bam.header().target_count()).map(|tid| bam.header().target_len(tid).unwrap_or(0) as i64).collect();
+    // Querent indices by bam tid, so reads are matched to target contigs without resolving their names
+    let target_tids = contig_indices(&bam_chroms, &querents);
+    let site_tids = contig_indices(&bam_chroms, &site_querents);
+    let extra_tids = contig_indices(&bam_chroms, &extra_querents);
+    let mut bam_records = 0u64; // all alignment records, before any filter
+    let mut primary_records = 0u64; // records that are neither secondary nor supplementary, one per read
+    let mut unmapped_reads = 0u64;
+    let mut counted_reads = 0u64; // mapped reads that passed filters
+    let mut bed_contig_reads = 0u64;
+    let mut on_target_reads = 0u64;
+    let mut position_duplicates = 0u64;
+    let mut fragment_filtered = 0u64;
+    let mut unpaired_filtered = 0u64;
+    let mut target_duplicates = 0u64; // on target reads flagged as duplicate, also the ones skipped by flag filter
+    let mut skipped_target_duplicates = 0u64;
+    let mut ungrouped_reads = 0u64;
+    let mut unknown_contig_reads = 0u64; // mapped reads with a tid beyond the bam header
+    let mut primer_bases = 0u64;
+    let primers = cli.primers.as_ref().map(|path| read_primers(path, &bam_chroms));
+    let mut dedup_position = (-1, -1);
+    let mut dedup_keys = FxHashSet::default();
+    let mut unsorted_bam = false;
+    let mut unique_start_position = (-1, -1);
+    let weight_scale = if cli.proportional_overlap { OVERLAP_SHARE_SCALE } else { 1 };
+    let mut overlap_spans = Vec::new();
+    let mut read = AlignedRead{ start: 0, end: 0, insert_size: 0, weight: weight_scale, strand: Strand::Unknown, blocks: Vec::new(), group: None, primer_spans: Vec::new(), gaps: Vec::new(), five_prime: None, start_key: None, share: None };
+    for r in bam.rc_records() {
+        let record = r.expect("Failure parsing Bam file");
+        bam_records += 1;
+        if cli.output_unmapped_summary && record.flags() & (FLAG_SECONDARY | FLAG_SUPPLEMENTARY) == 0 {
+            // Before the flag filter, which skips unmapped reads with the default exclude mask
+            primary_records += 1;
+            if record.flags() & FLAG_UNMAPPED != 0 || record.tid() < 0 {
+                unmapped_reads += 1;
+            }
+        }
+        if !flag_filter.passes(record.flags()) {
+            // Duplicates skipped only for the duplicate flag still count toward the duplicate rate
+            if record.is_duplicate() && record.tid() >= 0 && (record.tid() as usize) < contig_lengths.len() && record.mapq() >= cli.min_mapq && flag_filter.passes(record.flags() & !FLAG_DUPLICATE) && pairing_passes(&cli, &record) && fragment_length_passes(&cli, &record) {
+                let tid = record.tid() as usize;
+                if let Some((_, querent_chrom)) = target_tids[tid].and_then(|i| querents.get_index_mut(i)) {
+                    let (start, end) = counted_span(&record, span_ops, cli.include_softclip_span, contig_lengths[tid]);
+                    let mut on_target = false;
+                    querent_chrom.query((start-1) as i32, (end+1) as i32, |_| on_target = true);
+                    if on_target {
+                        skipped_target_duplicates += 1;
+                    }
+                }
+            }
+            continue;
+        }
+        if record.tid() < 0 {
+            continue;
+        }
+        if record.tid() as usize >= contig_lengths.len() {
+            // Not lumped with off-target reads, the read can't be placed at all
+            unknown_contig_reads += 1;
+            continue;
+        }
+        if record.mapq() < cli.min_mapq {
+            continue;
+        }
+        if !pairing_passes(&cli, &record) {
+            unpaired_filtered += 1;
+            continue;
+        }
+        if !fragment_length_passes(&cli, &record) {
+            fragment_filtered += 1;
+            continue;
+        }
+        counted_reads += 1;
+        if cli.mapq_weight {
+            read.weight = record.mapq().min(MAPQ_WEIGHT_CAP) as i64 * weight_scale;
+        }
+        if cli.insert_stats {
+            read.insert_size = if record.is_proper_pair() { record.insert_size().max(0) } else { 0 };
+        }
+        let tid = record.tid() as usize;
+        let cigar = record.cigar();
+        (read.start, read.end) = counted_span(&record, span_ops, cli.include_softclip_span, contig_lengths[tid]);
+        let (start, end) = (read.start, read.end);
+        // Soft clips extend the span back by different lengths, only the aligned start follows the bam order
+        let (aligned_start, aligned_end) = if cli.include_softclip_span { reference_span(&cigar, record.pos(), span_ops) } else { (start, end) };
+        if cli.dedup_by_position {
+            if (record.tid(), aligned_start) != dedup_position {
+                if record.tid() == dedup_position.0 && aligned_start < dedup_position.1 && !unsorted_bam {
+                    warnings.warn("bam file is not coordinate sorted, --dedup-by-position only removes duplicates of sorted input");
+                    unsorted_bam = true;
+                }
+                dedup_position = (record.tid(), aligned_start);
+                dedup_keys.clear();
+            }
+            if !dedup_keys.insert((aligned_end, record.is_reverse(), record.is_first_in_template(), record.mtid(), record.mpos())) {
+                position_duplicates += 1;
+                continue;
+            }
+        }
+        if !group_index.is_empty() {
+            read.group = match record.aux(b"RG") {
+                Ok(bam::record::Aux::String(group)) => group_index.get(group.as_bytes()).copied(),
+                _ => None,
+            };
+            if read.group.is_none() {
+                ungrouped_reads += 1;
+            }
+        }
+        if strand_match {
+            read.strand = if record.is_reverse() { Strand::Reverse } else { Strand::Forward };
+        }
+        if cli.count_mode == CountMode::Start5 {
+            read.five_prime = Some(if record.is_reverse() { end } else { start });
+        }
+        if cli.count_mode == CountMode::UniqueStart {
+            if (record.tid(), aligned_start) < unique_start_position && !unsorted_bam {
+                warnings.warn("bam file is not coordinate sorted, --count-mode unique-start counts a start again when its reads are not adjacent");
+                unsorted_bam = true;
+            }
+            unique_start_position = (record.tid(), aligned_start);
+            read.start_key = Some((aligned_start, record.is_reverse()));
+        }
+        if let Some(primers) = &primers {
+            // Primers are disjoint and sorted, only the ones from the first ending after read start can overlap it
+            let tid_primers = &primers[tid];
+            read.primer_spans.clear();
+            let first = tid_primers.partition_point(|&(_, primer_end)| primer_end < start);
+            for &(primer_start, primer_end) in tid_primers[first..].iter().take_while(|&&(primer_start, _)| primer_start <= end) {
+                read.primer_spans.push((primer_start.max(start), primer_end.min(end)));
+            }
+            primer_bases += read.primer_spans.iter().map(|(start, end)| (end - start + 1) as u64).sum::<u64>();
+        }
+        if let Some(del_gap) = cli.del_gap {
+            deletion_gaps(&cigar, record.reference_start(), span_ops, del_gap, &mut read.gaps);
+        }
+        if base_depth {
+            aligned_blocks(&cigar, record.reference_start(), span_ops, record.qual(), cli.min_base_quality, cli.del_gap, &mut read.blocks);
+        }
+
+        // Variant sites and flanks aren't split with --proportional-overlap, the share is only set for target regions
+        read.share = None;
+        if let Some((_, site_querent)) = site_tids[tid].and_then(|i| site_querents.get_index_mut(i)) {
+            site_querent.query(start as i32, end as i32, |node| {update_node(&read, node);});
+        }
+        if let Some((_, extra_querent)) = extra_tids[tid].and_then(|i| extra_querents.get_index_mut(i)) {
+            extra_querent.query(start as i32, end as i32, |node| {update_node(&read, node);});
+        }
+
+        let querent_chrom = match target_tids[tid].and_then(|i| querents.get_index_mut(i)) {
+            Some((_, querent_chrom)) => { bed_contig_reads += 1; querent_chrom },
+            _ => continue,
+        };
+        if cli.proportional_overlap {
+            // Overlaps with every region the read is counted for, before any of them is updated
+            overlap_spans.clear();
+            querent_chrom.query((start-1) as i32, (end+1) as i32, |node| if counts_read(&read, &node.metadata) { overlap_spans.push((node.first as i64, node.last as i64)) });
+            let overlap_sum: i64 = overlap_spans.iter().map(|&(first, last)| read.coverage(first, last)).sum();
+            let union: i64 = merge_intervals(&mut overlap_spans).iter().map(|&(first, last)| read.coverage(first, last)).sum();
+            read.share = Some((union, overlap_sum));
+        }
+        let mut on_target = false;
+        querent_chrom.query((start-1) as i32, (end+1) as i32, |node| {on_target |= update_node(&read, node)}); // Runs update_node on
+        // each interval in tree that has intersection with query interval
+        read.share = None;
+        if on_target {
+            on_target_reads += 1;
+            if record.is_duplicate() {
+                target_duplicates += 1;
+            }
+        }
+    }
+    target_duplicates += skipped_target_duplicates;
+
+    eprintln!("Finished processing bam file");
+    if ungrouped_reads > 0 {
+        eprintln!("{ungrouped_reads} reads have no read group from the bam header, they are only counted in the report");
+    }
+    if unknown_contig_reads > 0 {
+        warnings.warn(&format!("{unknown_contig_reads} reads have a reference id without a contig in the bam header, they are skipped, the header may be truncated or corrupt"));
+    }
+    if bam_records == 0 {
+        // Not a targeting problem, a truncated or header-only bam would otherwise look like a sample without coverage
+        warnings.warn(&format!("bam file {} has no alignment records, it may be truncated or contain only a header, all coverage values are zero", cli.bam));
+    } else if on_target_reads == 0 {
+        // Usually caused by wrong reference or bed, name the most likely cause
+        let reason = if counted_reads == 0 {
+            String::from("no mapped reads passed filters")
+        } else if bed_contig_reads == 0 {
+            format!("none of {counted_reads} mapped reads are on BED contigs, check that bed and bam use the same chromosome naming")
+        } else {
+            format!("{bed_contig_reads} reads are on BED contigs but none overlaps a region, check that bed and bam use the same reference build")
+        };
+        warnings.warn(&format!("No reads overlapping named BED regions were found, all coverage values are zero: {reason}"));
+    }
+
+    // Checked before any output file is created, so a failure under --strict leaves no partial outputs behind
+    for region in saturated_regions(&mut querents) {
+        warnings.warn(&format!("Coverage count of region {region} overflowed and is saturated, its depth is not reliable"));
+    }
+
+    if let Some((path, text)) = &target_bed {
+        std::fs::write(path, text).expect("Error writing target bed");
+    }
+
+    if let Some(vcf_output) = &cli.vcf_output {
+        eprintln!("Writing variant site depth into {vcf_output}");
+        let mut writer = BufWriter::new(File::create(vcf_output).expect("Error creating vcf depth output file"));
+        writeln!(writer, "CHROM\tPOS\tDEPTH").unwrap();
+        for chrom in &site_chrom_order {
+            let mut sites = Vec::new();
+            site_querents.get_mut(chrom).unwrap().query(0, i32::MAX, |node| sites.push((node.first, *node.metadata.count.borrow())));
+            sites.sort_unstable();
+            for (pos, depth) in sites {
+                if cli.mapq_weight {
+                    writeln!(writer, "{chrom}\t{pos}\t{:.2}", depth as f64 / count_scale).unwrap();
+                } else {
+                    // Without MAPQ weights the only scale is the read weight of --proportional-overlap, depth stays whole
+                    writeln!(writer, "{chrom}\t{pos}\t{}", depth / count_scale as i64).unwrap();
+                }
+            }
+        }
+    }
+
+    eprintln!("Outputing result into stdout");
+
+    // Prepare the header
+    let mut columns = vec!["Sample", "Gene", "Chr", "Start", "End", "Tag", "Length", "MeanDepth"];
+    if cli.both_lengths {
+        columns.extend(["LengthRaw", "LengthPerl"]);
+    }
+    if gene_min_depth.is_some() {
+        columns.push("Status");
+    }
+    if cli.callable.is_some() {
+        columns.push("CallableBases");
+    }
+    if cli.zero_bases {
+        columns.push("ZeroBases");
+    }
+    let threshold_columns: Vec<String> = cli.thresholds.iter().map(|threshold| format!("Fraction{threshold}x")).collect();
+    columns.extend(threshold_columns.iter().map(String::as_str));
+    if cli.depth_sd {
+        columns.push("DepthSD");
+    }
+    if cli.entropy {
+        columns.push("Entropy");
+    }
+    if cli.midpoint_depth {
+        columns.push("MidpointDepth");
+    }
+    if cli.motif.is_some() {
+        columns.extend(["MotifSites", "MotifDepth"]);
+    }
+    match cli.count_mode {
+        CountMode::Span => (),
+        CountMode::Start5 => columns.push("StartCount"),
+        CountMode::UniqueStart => columns.push("UniqueStarts"),
+    }
+    if cli.insert_stats {
+        columns.extend(["MeanInsertSize", "MedianInsertSize"]);
+    }
+    if cli.flank.is_some() {
+        columns.extend(["LeftFlankDepth", "RightFlankDepth"]);
+    }
+    if cli.gene_lengths.is_some() {
+        columns.push("TargetedFraction");
+    }
+    if cli.baseline.is_some() {
+        columns.push("BaselineRatio");
+    }
+    if cli.rank {
+        columns.push("Rank");
+    }
+    if cli.normalize.is_some() {
+        columns.push("Log2Ratio");
+    }
+    let mut table = Table{ columns: columns.into_iter().map(String::from).collect(), rows: Vec::new() };
+    let (mut covered_amplicons, mut covered_amplicons_total) = (0, 0); // for --covered-summary
+    let (mut covered_genes, mut covered_genes_total) = (0, 0);
+    let group_map = cli.group_map.as_deref().map(read_group_map);
+    let gene_lengths = cli.gene_lengths.as_deref().map(read_gene_lengths);
+    let mut group_summaries = FnvIndexMap::<String, GroupSummary>::default();
+    let reported_depth = |depth: f64| if cli.log2_depth { (depth + cli.pseudocount).log2() } else { depth };
+    // Row index and depth of every amplicon, ratios are filled in once the sample mean is known
+    let mut amplicon_depths = Vec::new();
+    let mut gene_depths = Vec::new(); // row index and depth of Whole-Gene rows for --rank
+    let mut gene_blocks = Vec::new(); // gene and its rows for --gene-order, a gene on several chromosomes has a block for each
+    let (mut sample_count, mut sample_length) = (0.0, 0i64);
+    let mut amplicon_coverage = Vec::new(); // depth and length of every amplicon for fold-80 of the json qc block
+    let mut short_names = 0;
+    let mut amplicon_bed_writer = cli.amplicon_bed.as_ref().map(|path| -> Box<dyn Write> {
+        if path.ends_with(".gz") {
+            Box::new(bgzf::Writer::from_path(path).expect("Error creating amplicon bed output file"))
+        } else {
+            Box::new(BufWriter::new(File::create(path).expect("Error creating amplicon bed output file")))
+        }
+    });
+    let mut callable_writer = cli.callable.as_ref().map(|path| BufWriter::new(File::create(path).expect("Error creating callable regions output file")));
+    // Rows only depend on the bed, so matrices of samples run with the same panel line up
+    let mut matrix_writer = cli.matrix.as_ref().map(|path| BufWriter::new(File::create(path).expect("Error creating matrix output file")));
+    if let Some(writer) = matrix_writer.as_mut() {
+        writeln!(writer, "Gene\tChr\tStart\tEnd\t{sample_name}").unwrap();
+    }
+    let mut group_writer = cli.split_by_rg.as_ref().map(|path| BufWriter::new(File::create(path).expect("Error creating read group matrix output file")));
+    if let Some(writer) = group_writer.as_mut() {
+        writeln!(writer, "Gene\tChr\tStart\tEnd{}", groups.iter().map(|group| format!("\t{group}")).collect::<String>()).unwrap();
+    }
+
+    for chrom in bed_chrom_order {
+        let chrom_tree = querents.get_mut(&chrom).unwrap(); //Safe to unwrap since it's guaranteed that we will have a hit
+        //let mut output = chrom_tree.iter()
+        let mut output = Vec::new();
+        chrom_tree.query(0, i32::MAX, |node| {output.push(OutputRegion{gene:aggregation_key(&node.metadata.name, cli.name_delimiter.as_deref(), cli.aggregate_field)
+                                                            .map_or_else(|| { short_names += 1; node.metadata.name.clone() }, str::to_string),
+                                                        start:node.first as i64,
+                                                        end:node.last as i64,
+                                                        name:node.metadata.name.clone(),
+                                                        count:*node.metadata.count.borrow(),
+                                                        depth:node.metadata.depth.take(),
+                                                        n_bases:node.metadata.n_bases,
+                                                        motif_sites:node.metadata.motif_sites.clone(),
+                                                        score_weight:node.metadata.score_weight,
+                                                        strand:node.metadata.strand,
+                                                        group_counts:node.metadata.group_counts.take(),
+                                                        input_index:node.metadata.input_index,
+                                                        midpoint_count:node.metadata.midpoint_count.get(),
+                                                        start_count:node.metadata.start_count.get(),
+                                                        unique_starts:node.metadata.unique_starts.get(),
+                                                        insert_sizes:node.metadata.insert_sizes.take()})
+                                            });
+
+        let mut extra_counts = FxHashMap::default();
+        if let Some(extra_querent) = extra_querents.get_mut(&chrom) {
+            extra_querent.query(0, i32::MAX, |node| { extra_counts.insert((node.first as i64, node.last as i64, node.metadata.strand.strand_symbol().to_string()), *node.metadata.count.borrow()); });
+        }
+        let querent_index = querents.get_index_of(&chrom);
+        let contig_length = target_tids.iter().position(|&index| index.is_some() && index == querent_index).map_or(i64::MAX, |tid| contig_lengths[tid]);
+        // Mean depth of a flank over its part inside the contig, Missing for flanks completely outside of it
+        let flank_depth = |first: i64, last: i64, strand: Strand| {
+            let Some((first, last)) = clip_to_contig(first, last, contig_length) else { return Missing };
+            match extra_counts.get(&(first, last, strand.strand_symbol().to_string())) {
+                Some(&count) => Float(count as f64 / count_scale / (last - first + 1) as f64, 2),
+                None => Missing,
+            }
+        };
+        if cli.keep_region_order {
+            output.sort_by(|a, b| (&a.gene, a.input_index).cmp(&(&b.gene, b.input_index)));
+        } else {
+            // Ties are broken by bed order, so counts never affect the order of rows
+            output.sort_by(|a, b| (&a.gene, &a.name, a.start, a.end, a.input_index).cmp(&(&b.gene, &b.name, b.start, b.end, b.input_index)));
+        }
+
+        let mut callable_runs = Vec::new();
+        let mut amplicon_records = Vec::new();
+        for gene_regions in output.chunk_by(|a, b| a.gene == b.gene) {
+            let current_gene = &gene_regions[0].gene;
+            let current_start = gene_regions.iter().map(|region| region.start).min().unwrap();
+            let mut current_end = 0;
+            let mut total_length = 0;
+            let mut total_raw_length = 0; // both conventions for --both-lengths
+            let mut total_perl_length = 0;
+            let mut total_count = 0.0; // weighted by region scores
+            let mut gene_runs = Vec::new();
+            let mut gene_spans = Vec::new();
+            let mut gene_covered = Vec::new();
+            let mut gene_threshold_covered = vec![Vec::new(); cli.thresholds.len()];
+            let mut gene_summary_covered = Vec::new();
+            let mut gene_depth_runs = Vec::new();
+            let mut gene_insert_sizes = BTreeMap::new();
+            let mut gene_motif_sites = Vec::new();
+            let mut gene_motif_depths = Vec::new(); // position and depth of motif bases, once per position
+
+            let gene_first_row = table.rows.len();
+            // Collapsed amplicons are reported as one row of the merged span at the first amplicon of the cluster
+            let (clusters, mut collapsed) = match cli.collapse_amplicons {
+                Some(min_overlap) => {
+                    let spans: Vec<(i64, i64)> = gene_regions.iter().map(|region| (region.start, region.end)).collect();
+                    let strands: Vec<Strand> = gene_regions.iter().map(|region| region.strand).collect();
+                    let (clusters, merged) = collapsed_spans(&spans, &strands, min_overlap);
+                    (clusters, merged.into_iter().map(|(cluster, span)| (cluster, (span, false))).collect())
+                },
+                None => (Vec::new(), FxHashMap::default()),
+            };
+            for (i, region) in gene_regions.iter().enumerate() {
+                let length = if mimic_perl_output {
+                    region.end - region.start + 1 //Length in perl version of seq2c calculated +1
+                } else {
+                    region.end - region.start
+                } - region.n_bases;
+
+                let count = region.count;
+                if region.end > current_end {
+                    current_end = region.end;
+                }
+                let weighted_count = count as f64 / region.score_weight;
+                let depth = if length > 0 { weighted_count / count_scale / length as f64 } else { 0.0 };
+                let mut row = vec![Text(sample_name.clone()), Text(region.gene.clone()), Text(chrom.clone()), Int(region.start), Int(region.end),
+                                   Text(cli.amplicon_tag.clone()), Int(length), Float(reported_depth(depth), 2)];
+                if let Some(writer) = matrix_writer.as_mut() {
+                    writeln!(writer, "{}\t{chrom}\t{}\t{}\t{depth:.2}", region.gene, region.start + cli.coord_base, region.end).unwrap();
+                }
+                if let Some(writer) = group_writer.as_mut() {
+                    let depths: String = region.group_counts.iter()
+                        .map(|&group_count| if length > 0 { group_count as f64 / region.score_weight / count_scale / length as f64 } else { 0.0 })
+                        .map(|group_depth| format!("\t{group_depth:.2}"))
+                        .collect();
+                    writeln!(writer, "{}\t{chrom}\t{}\t{}{depths}", region.gene, region.start + cli.coord_base, region.end).unwrap();
+                }
+                if cli.both_lengths {
+                    let raw_length = region.end - region.start - region.n_bases;
+                    row.extend([Int(raw_length), Int(raw_length + 1)]);
+                    total_raw_length += raw_length;
+                    total_perl_length += raw_length + 1;
+                }
+                if amplicon_bed_writer.is_some() {
+                    amplicon_records.push((region.start, region.end, &region.name, depth.round().clamp(0.0, 1000.0) as u32));
+                }
+                if gene_min_depth.is_some() {
+                    row.push(Empty);
+                }
+                if cli.callable.is_some() {
+                    let runs = region.depth.ranges_at_least(cli.callable_depth);
+                    row.push(Int(runs.iter().map(|(start, end)| end - start).sum::<i64>()));
+                    gene_runs.extend(runs.iter().map(|(start, end)| (region.start + start, region.start + end)));
+                }
+                if cli.zero_bases {
+                    let covered = region.depth.ranges_at_least(1);
+                    row.push(Int(region.depth.len() as i64 - covered.iter().map(|(start, end)| end - start).sum::<i64>()));
+                    gene_covered.extend(covered.iter().map(|(start, end)| (region.start + start, region.start + end)));
+                }
+                if cli.covered_summary {
+                    let covered = region.depth.ranges_at_least(cli.covered_depth);
+                    let covered_length: i64 = covered.iter().map(|(start, end)| end - start).sum();
+                    if !region.depth.is_empty() && covered_length as f64 >= cli.covered_breadth * region.depth.len() as f64 {
+                        covered_amplicons += 1;
+                    }
+                    covered_amplicons_total += 1;
+                    gene_summary_covered.extend(covered.iter().map(|(start, end)| (region.start + start, region.start + end)));
+                }
+                if cli.zero_bases || !cli.thresholds.is_empty() || cli.covered_summary {
+                    gene_spans.push((region.start, region.start + region.depth.len() as i64));
+                }
+                for (&threshold, gene_threshold_covered) in cli.thresholds.iter().zip(gene_threshold_covered.iter_mut()) {
+                    let covered = region.depth.ranges_at_least(threshold);
+                    let covered_length: i64 = covered.iter().map(|(start, end)| end - start).sum();
+                    row.push(if region.depth.is_empty() { Missing } else { Float(covered_length as f64 / region.depth.len() as f64, 4) });
+                    gene_threshold_covered.extend(covered.iter().map(|(start, end)| (region.start + start, region.start + end)));
+                }
+                if cli.depth_sd {
+                    row.push(match region.depth.sd() {
+                        Some(sd) => Float(sd, 2),
+                        None => Missing,
+                    });
+                }
+                if cli.entropy {
+                    row.push(Empty);
+                    gene_depth_runs.extend(region.depth.runs().into_iter().map(|(start, end, depth)| (region.start + start, region.start + end, depth)));
+                }
+                if cli.midpoint_depth {
+                    row.push(Float(region.midpoint_count as f64 / count_scale, 2));
+                }
+                if let Some(motif) = &motif {
+                    let bases = motif_bases(&region.motif_sites, motif.len() as i64);
+                    let depths = region.depth.depths_at(&bases);
+                    row.push(Int(region.motif_sites.len() as i64));
+                    row.push(if depths.is_empty() { Missing } else { Float(depths.iter().map(|&depth| depth as f64).sum::<f64>() / depths.len() as f64, 2) });
+                    gene_motif_sites.extend(region.motif_sites.iter().map(|site| region.start + site));
+                    gene_motif_depths.extend(bases.iter().zip(depths).map(|(base, depth)| (region.start + base, depth)));
+                }
+                match cli.count_mode {
+                    CountMode::Span => (),
+                    CountMode::Start5 => row.push(Int(region.start_count)),
+                    CountMode::UniqueStart => row.push(Int(region.unique_starts)),
+                }
+                if cli.insert_stats {
+                    row.extend([Empty, Empty]);
+                    for (&insert_size, &n) in &region.insert_sizes {
+                        *gene_insert_sizes.entry(insert_size).or_insert(0) += n;
+                    }
+                }
+                if let Some(flank) = cli.flank {
+                    let flank = flank as i64;
+                    row.push(flank_depth(region.start - flank, region.start - 1, region.strand));
+                    row.push(flank_depth(region.end + 1, region.end + flank, region.strand));
+                }
+                if cli.gene_lengths.is_some() {
+                    row.push(Empty);
+                }
+                if let Some(baseline) = &baseline {
+                    let baseline_depth = match baseline {
+                        Baseline::Counts(counts) => counts.get(&(chrom.clone(), region.start, region.end)).map(|&count| if length > 0 { count / region.score_weight / length as f64 } else { 0.0 }),
+                        Baseline::Depths(depths) => depths.get(&(chrom.clone(), region.start, region.end)).copied(),
+                    };
+                    row.push(baseline_depth.map_or(Missing, |baseline_depth| Float(depth / baseline_depth.max(BASELINE_DEPTH_FLOOR), 2)));
+                }
+                if cli.rank {
+                    row.push(Empty);
+                }
+                if cli.normalize.is_some() {
+                    row.push(Empty);
+                }
+                sample_count += weighted_count;
+                sample_length += length;
+                amplicon_coverage.push((depth, length));
+                if let Some(group_map) = &group_map {
+                    let group = group_map.get(&region.name).map_or("ungrouped", String::as_str);
+                    let summary = group_summaries.entry(group.to_string())
+                        .or_insert(GroupSummary{ chrom: Some(chrom.clone()), start: region.start, end: region.end, length: 0, count: 0.0 });
+                    if summary.chrom.as_ref() != Some(&chrom) {
+                        summary.chrom = None;
+                    }
+                    summary.start = summary.start.min(region.start);
+                    summary.end = summary.end.max(region.end);
+                    summary.length += length;
+                    summary.count += weighted_count;
+                }
+                match clusters.get(i).and_then(|cluster| collapsed.get_mut(cluster)) {
+                    None => {
+                        if cli.normalize.is_some() {
+                            amplicon_depths.push((table.rows.len(), depth));
+                        }
+                        table.rows.push(row);
+                    },
+                    Some((_, true)) => (),
+                    Some(((start, end, strand), reported)) => {
+                        // Columns of single amplicons don't apply to the merged span
+                        let length = *end - *start + if mimic_perl_output { 1 } else { 0 };
+                        let count = extra_counts.get(&(*start, *end, strand.strand_symbol().to_string())).copied().unwrap_or(0);
+                        let depth = if length > 0 { count as f64 / count_scale / length as f64 } else { 0.0 };
+                        let mut row = vec![Text(sample_name.clone()), Text(region.gene.clone()), Text(chrom.clone()), Int(*start), Int(*end),
+                                           Text(cli.amplicon_tag.clone()), Int(length), Float(reported_depth(depth), 2)];
+                        if cli.both_lengths {
+                            row.extend([Int(*end - *start), Int(*end - *start + 1)]);
+                        }
+                        row.resize_with(table.columns.len(), || Empty);
+                        table.rows.push(row);
+                        *reported = true;
+                    },
+                }
+                if let Some(window) = cli.window {
+                    // Bins are 0-based half-open like the per-base depth, Length is the real number of bases
+                    for (offset, bin_length, bin_depth) in region.depth.window_means(window as i64) {
+                        let bin_start = region.start + offset;
+                        let mut row = vec![Text(sample_name.clone()), Text(region.gene.clone()), Text(chrom.clone()), Int(bin_start), Int(bin_start + bin_length),
+                                           Text(String::from("Window")), Int(bin_length), Float(reported_depth(bin_depth), 2)];
+                        row.resize_with(table.columns.len(), || Empty);
+                        table.rows.push(row);
+                    }
+                }
+                total_length += length;
+                total_count += weighted_count;
+            }
+
+            // Aggregated line for the whole gene
+            let mean_depth = if total_length > 0 { total_count / count_scale / total_length as f64} else { 0.0 };
+            let mut row = vec![Text(sample_name.clone()), Text(current_gene.clone()), Text(chrom.clone()), Int(current_start), Int(current_end),
+                               Text(cli.gene_tag.clone()), Int(total_length), Float(reported_depth(mean_depth), 2)];
+            if cli.both_lengths {
+                row.extend([Int(total_raw_length), Int(total_perl_length)]);
+            }
+            if let Some(min_depth) = gene_min_depth {
+                if mean_depth >= min_depth {
+                    row.push(Text(String::from("PASS")));
+                } else {
+                    row.push(Text(String::from("FAIL")));
+                }
+            }
+            if cli.callable.is_some() {
+                let gene_runs = merge_intervals(&mut gene_runs);
+                row.push(Int(gene_runs.iter().map(|(start, end)| end - start).sum::<i64>()));
+                callable_runs.extend(gene_runs.into_iter().map(|(start, end)| (start, end, current_gene.clone())));
+            }
+            if cli.zero_bases {
+                let union_length: i64 = merge_intervals(&mut gene_spans).iter().map(|(start, end)| end - start).sum();
+                let covered_length: i64 = merge_intervals(&mut gene_covered).iter().map(|(start, end)| end - start).sum();
+                row.push(Int(union_length - covered_length));
+            }
+            if !cli.thresholds.is_empty() {
+                // Over the union of gene regions, overlapping amplicons count their bases once
+                let union_length: i64 = merge_intervals(&mut gene_spans).iter().map(|(start, end)| end - start).sum();
+                for covered in gene_threshold_covered.iter_mut() {
+                    let covered_length: i64 = merge_intervals(covered).iter().map(|(start, end)| end - start).sum();
+                    row.push(if union_length > 0 { Float(covered_length as f64 / union_length as f64, 4) } else { Missing });
+                }
+            }
+            if cli.covered_summary {
+                // Breadth over the union of gene regions like the threshold columns
+                let union_length: i64 = merge_intervals(&mut gene_spans).iter().map(|(start, end)| end - start).sum();
+                let covered_length: i64 = merge_intervals(&mut gene_summary_covered).iter().map(|(start, end)| end - start).sum();
+                if union_length > 0 && covered_length as f64 >= cli.covered_breadth * union_length as f64 {
+                    covered_genes += 1;
+                }
+                covered_genes_total += 1;
+            }
+            if cli.depth_sd {
+                row.push(Empty);
+            }
+            if cli.entropy {
+                // Bases shared by overlapping amplicons count once, their depth is the same in each of them
+                gene_depth_runs.sort_unstable();
+                let mut covered_until = i64::MIN;
+                let runs: Vec<(i64, i64, u32)> = gene_depth_runs.iter().filter_map(|&(start, end, depth)| {
+                    let start = start.max(covered_until);
+                    covered_until = covered_until.max(end);
+                    (start < end).then_some((start, end, depth))
+                }).collect();
+                row.push(depth_entropy(&runs).map_or(Missing, |entropy| Float(entropy, 4)));
+            }
+            if cli.midpoint_depth {
+                row.push(Empty);
+            }
+            if cli.motif.is_some() {
+                // Sites and bases shared by overlapping amplicons count once
+                gene_motif_sites.sort_unstable();
+                gene_motif_sites.dedup();
+                gene_motif_depths.sort_unstable();
+                gene_motif_depths.dedup_by_key(|&mut (position, _)| position);
+                row.push(Int(gene_motif_sites.len() as i64));
+                row.push(if gene_motif_depths.is_empty() { Missing } else { Float(gene_motif_depths.iter().map(|&(_, depth)| depth as f64).sum::<f64>() / gene_motif_depths.len() as f64, 2) });
+            }
+            if cli.count_mode != CountMode::Span {
+                row.push(Empty);
+            }
+            if cli.insert_stats {
+                match (histogram_mean(&gene_insert_sizes), histogram_median(&gene_insert_sizes)) {
+                    (Some(mean), Some(median)) => row.extend([Float(mean, 2), Float(median, 1)]),
+                    _ => row.extend([Missing, Missing]),
+                }
+            }
+            if cli.flank.is_some() {
+                row.extend([Empty, Empty]);
+            }
+            if let Some(gene_lengths) = &gene_lengths {
+                let mut spans: Vec<(i64, i64)> = gene_regions.iter().map(|region| (region.start, region.end)).collect();
+                let targeted: i64 = merge_intervals(&mut spans).iter().map(|(start, end)| end - start).sum();
+                row.push(gene_lengths.get(current_gene).map_or(Missing, |&gene_length| Float(targeted as f64 / gene_length as f64, 4)));
+            }
+            if cli.baseline.is_some() {
+                row.push(Empty);
+            }
+            if cli.rank {
+                row.push(Empty);
+            }
+            if cli.normalize.is_some() {
+                row.push(Empty);
+            }
+            if !(cli.no_amplicon_aggregate_when_single && gene_regions.len() == 1) {
+                if cli.rank {
+                    gene_depths.push((table.rows.len(), mean_depth));
+                }
+                table.rows.push(row);
+            }
+            if cli.suppress_empty_genes && gene_regions.iter().all(|region| region.count == 0) {
+                table.rows.truncate(gene_first_row);
+                amplicon_depths.retain(|&(row, _)| row < gene_first_row);
+                gene_depths.retain(|&(row, _)| row < gene_first_row);
+            }
+            if cli.gene_order.is_some() {
+                gene_blocks.push((current_gene.clone(), gene_first_row..table.rows.len()));
+            }
+        }
+
+        if let Some(writer) = amplicon_bed_writer.as_mut() {
+            amplicon_records.sort_unstable();
+            for (start, end, name, score) in amplicon_records {
+                writeln!(writer, "{chrom}\t{start}\t{end}\t{name}\t{score}").unwrap();
+            }
+        }
+        if let Some(writer) = callable_writer.as_mut() {
+            callable_runs.sort_unstable();
+            for (start, end, gene) in callable_runs {
+                writeln!(writer, "{chrom}\t{start}\t{end}\t{gene}").unwrap();
+            }
+        }
+    }
+
+    // Each contig is written once with records sorted by start, as tabix needs, the index is built once the bgzf EOF block is written
+    drop(amplicon_bed_writer);
+    if let Some(path) = cli.amplicon_bed.as_ref().filter(|path| path.ends_with(".gz")) {
+        tabix_index_bed(path).unwrap_or_else(|e| fail(&e));
+    }
+
+    // Group rows follow all genes, columns other than depth don't apply to them
+    for (group, summary) in group_summaries {
+        let mean_depth = if summary.length > 0 { summary.count / count_scale / summary.length as f64 } else { 0.0 };
+        let mut row = match summary.chrom {
+            Some(chrom) => vec![Text(sample_name.clone()), Text(group), Text(chrom), Int(summary.start), Int(summary.end)],
+            None => vec![Text(sample_name.clone()), Text(group), Text(String::from(".")), Text(String::from(".")), Text(String::from("."))],
+        };
+        row.extend([Text(cli.group_tag.clone()), Int(summary.length), Float(reported_depth(mean_depth), 2)]);
+        row.resize_with(table.columns.len(), || Empty);
+        table.rows.push(row);
+    }
+
+    let sample_mean = if sample_length > 0 { sample_count / count_scale / sample_length as f64 } else { 0.0 };
+    if let Some(Normalization::SampleMean) = cli.normalize {
+        eprintln!("Sample mean depth over amplicons: {sample_mean:.2}");
+        for (row, depth) in amplicon_depths {
+            *table.rows[row].last_mut().unwrap() = if sample_mean > 0.0 { Float((depth.max(LOG2_DEPTH_FLOOR) / sample_mean).log2(), 2) } else { Missing };
+        }
+    }
+
+    if let Some(rank_column) = table.columns.iter().position(|column| *column == "Rank") {
+        // Percentile of genes with lower depth, ties count half, so equal depths get equal ranks
+        let mut sorted: Vec<f64> = gene_depths.iter().map(|&(_, depth)| depth).collect();
+        sorted.sort_by(f64::total_cmp);
+        for &(row, depth) in &gene_depths {
+            let below = sorted.partition_point(|&other| other < depth);
+            let equal = sorted.partition_point(|&other| other <= depth) - below;
+            table.rows[row][rank_column] = Float(100.0 * (below as f64 + 0.5 * equal as f64) / sorted.len() as f64, 1);
+        }
+    }
+
+    if let Some(path) = &cli.gene_order {
+        // Blocks are moved as a whole after all row indices were used, group rows stay at the end
+        let gene_order = read_gene_order(path);
+        let listed = gene_blocks.iter().map(|(gene, _)| gene).filter(|gene| gene_order.contains_key(*gene)).collect::<FxHashSet<_>>().len();
+        if listed < gene_order.len() {
+            eprintln!("{} genes of {path} are not in the report", gene_order.len() - listed);
+        }
+        let genes_end = gene_blocks.last().map_or(0, |(_, rows)| rows.end);
+        let mut rows: Vec<Option<Vec<output::Value>>> = table.rows.drain(..).map(Some).collect();
+        gene_blocks.retain(|(gene, _)| !cli.drop_unlisted_genes || gene_order.contains_key(gene));
+        gene_blocks.sort_by_key(|(gene, _)| gene_order.get(gene).copied().unwrap_or(usize::MAX));
+        for (_, block) in gene_blocks {
+            table.rows.extend(rows[block].iter_mut().map(|row| row.take().unwrap()));
+        }
+        table.rows.extend(rows.drain(genes_end..).flatten());
+    }
+
+    // Counted on the final rows, genes whose Whole-Gene row was left out of the report have no Status
+    let status_column = table.columns.iter().position(|column| *column == "Status");
+    let genes_with_status = |status: &str| status_column.map_or(0, |column| table.rows.iter().filter(|row| matches!(&row[column], Text(text) if text == status)).count());
+    let (passed_genes, failed_genes) = (genes_with_status("PASS"), genes_with_status("FAIL"));
+
+    if cli.coord_base == 1 {
+        // Only the reported start moves, End is the last base in both conventions
+        let start_column = table.columns.iter().position(|column| *column == "Start").unwrap();
+        for row in &mut table.rows {
+            if let Int(start) = &mut row[start_column] {
+                *start += 1;
+            }
+        }
+    }
+
+    let mut comments = if cli.emit_provenance { provenance_header(&cli) } else { String::new() };
+    if cli.report_format_version {
+        comments += &format!("{}format version: {FORMAT_VERSION}\n", cli.comment_prefix);
+        comments += &format!("{}coordinate base: {}\n", cli.comment_prefix, cli.coord_base);
+    }
+    let on_target_fraction = if counted_reads > 0 { on_target_reads as f64 / counted_reads as f64 } else { 0.0 };
+    let target_reads = on_target_reads + skipped_target_duplicates;
+    let duplicate_rate = if target_reads > 0 { 100.0 * target_duplicates as f64 / target_reads as f64 } else { 0.0 };
+    let qc = [
+        ("reads_counted", Int(counted_reads as i64)),
+        ("reads_on_target", Int(on_target_reads as i64)),
+        ("on_target_fraction", Float(on_target_fraction, 4)),
+        ("mean_depth", Float(sample_mean, 2)),
+        ("fold_80_base_penalty", fold_80_penalty(&mut amplicon_coverage).map_or(Missing, |penalty| Float(penalty, 2))),
+        ("duplicate_rate", Float(duplicate_rate / 100.0, 4)),
+    ];
+    for (format, path) in cli.format.iter().zip(report_paths(&cli)) {
+        if cli.append {
+            append_tsv(path, &table, &comments, &cli.comment_prefix, bam_threads).unwrap_or_else(|e| fail(&e));
+            continue;
+        }
+        let mut writer: Box<dyn Write> = if path == "-" {
+            Box::new(BufWriter::new(std::io::stdout().lock()))
+        } else {
+            Box::new(BufWriter::new(File::create(path).expect("Error creating output file")))
+        };
+        match format {
+            OutputFormat::Tsv => table.write_tsv(&mut writer, &comments, bam_threads),
+            OutputFormat::Json => table.write_json(&mut writer, &sample_name, cli.coord_base, &qc),
+        }.and_then(|_| writer.flush()).expect("Error writing output");
+    }
+
+    if short_names > 0 {
+        warnings.warn(&format!("{short_names} region names have no field {}, whole name is used as gene", cli.aggregate_field));
+    }
+
+    if let Some(min_depth) = gene_min_depth {
+        eprintln!("{passed_genes} genes have mean depth of at least {min_depth}, {failed_genes} genes are below it");
+    }
+
+    eprintln!("Summary:");
+    eprintln!("  Genes: {}", panel_genes.len());
+    eprintln!("  Amplicons: {panel_amplicons}");
+    eprintln!("  Target bases (merged): {panel_bases}");
+    eprintln!("  Reads counted: {counted_reads}");
+    eprintln!("  Reads on target: {on_target_reads}");
+    if cli.dedup_by_position {
+        eprintln!("  Position duplicates skipped: {position_duplicates}");
+    }
+    if cli.paired_only {
+        eprintln!("  Single-end and orphaned reads skipped: {unpaired_filtered}");
+    }
+    if cli.min_fragment_length.is_some() || cli.max_fragment_length.is_some() {
+        eprintln!("  Reads skipped by fragment length: {fragment_filtered}");
+    }
+    if unknown_contig_reads > 0 {
+        eprintln!("  Reads with unknown contig skipped: {unknown_contig_reads}");
+    }
+    let unmapped_fraction = if primary_records > 0 { unmapped_reads as f64 / primary_records as f64 } else { 0.0 };
+    if cli.output_unmapped_summary {
+        eprintln!("  Unmapped reads: {unmapped_reads} of {primary_records} ({:.2}%)", 100.0 * unmapped_fraction);
+    }
+    if cli.primers.is_some() {
+        eprintln!("  Read bases clipped by primers: {primer_bases}");
+    }
+    if cli.covered_summary {
+        let breadth = (1000.0 * cli.covered_breadth).round() / 10.0;
+        eprintln!("  Amplicons with at least {breadth}% of bases at {}x: {covered_amplicons} of {covered_amplicons_total}", cli.covered_depth);
+        eprintln!("  Genes with at least {breadth}% of bases at {}x: {covered_genes} of {covered_genes_total}", cli.covered_depth);
+    }
+    eprintln!("  Duplicate rate on target: {duplicate_rate:.2}% ({target_duplicates} of {target_reads} reads)");
+
+    if let Some(path) = &cli.manifest {
+        let mut stats = vec![
+            ("genes", panel_genes.len().to_string()),
+            ("amplicons", panel_amplicons.to_string()),
+            ("target_bases", panel_bases.to_string()),
+            ("reads_counted", counted_reads.to_string()),
+            ("reads_on_target", on_target_reads.to_string()),
+            ("reads_skipped_as_unpaired", unpaired_filtered.to_string()),
+            ("reads_skipped_by_fragment_length", fragment_filtered.to_string()),
+            ("reads_skipped_with_unknown_contig", unknown_contig_reads.to_string()),
+            ("primer_bases_clipped", primer_bases.to_string()),
+            ("on_target_fraction", format!("{on_target_fraction:.4}")),
+            ("duplicate_rate", format!("{:.4}", duplicate_rate / 100.0)),
+            ("sample_mean_depth", format!("{sample_mean:.2}")),
+            ("warnings", warnings.raised.get().to_string()),
+        ];
+        if cli.output_unmapped_summary {
+            stats.extend([("unmapped_reads", unmapped_reads.to_string()), ("unmapped_fraction", format!("{unmapped_fraction:.4}"))]);
+        }
+        if cli.covered_summary {
+            stats.extend([("covered_amplicons", covered_amplicons.to_string()), ("covered_genes", covered_genes.to_string())]);
+        }
+        write_manifest(path, &cli, matches, started, &stats).expect("Error writing manifest");
+    }
+
+    // All gates are reported before exiting, so one run shows every reason a sample failed
+    let mut qc_failed = false;
+    if let Some(threshold) = cli.fail_below {
+        if sample_mean < threshold {
+            eprintln!("QC failed: sample mean depth {sample_mean:.2} is below {threshold}");
+            qc_failed = true;
+        } else {
+            eprintln!("QC passed: sample mean depth {sample_mean:.2} is at least {threshold}");
+        }
+    }
+    if let (Some(min_genes), Some(min_depth)) = (cli.min_genes_covered, gene_min_depth) {
+        if passed_genes < min_genes {
+            eprintln!("QC failed: {passed_genes} genes reach mean depth {min_depth}, at least {min_genes} are required");
+            qc_failed = true;
+        } else {
+            eprintln!("QC passed: {passed_genes} genes reach mean depth {min_depth}, at least {min_genes} are required");
+        }
+    }
+    if qc_failed {
+        std::process::exit(2);
+    }
+
+    eprintln!("Done");
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flag_filter(options: &[&str]) -> FlagFilter {
+        let args = ["seq2c-rs", "-b", "sample.bam", "-N", "sample", "-p", "panel.bed"].into_iter().chain(options.iter().copied());
+        FlagFilter::new(&Cli::try_parse_from(args).unwrap().count.unwrap())
+    }
+
+    #[test]
+    fn default_flags_skip_secondary_and_count_duplicates() {
+        let filter = flag_filter(&[]);
+        assert!(filter.passes(0));
+        assert!(filter.passes(FLAG_DUPLICATE));
+        assert!(filter.passes(0x1 | 0x2 | 0x10 | 0x40)); // paired reverse first mate
+        for flag in [FLAG_UNMAPPED, FLAG_SECONDARY, 0x200, FLAG_SUPPLEMENTARY] {
+            assert!(!filter.passes(flag), "flag {flag:#x}");
+            assert!(!filter.passes(flag | FLAG_DUPLICATE), "flag {flag:#x}");
+        }
+    }
+
+    #[test]
+    fn required_flag_wins_over_default_exclude() {
+        let filter = flag_filter(&["--require-flags", "0x100"]);
+        assert!(filter.passes(FLAG_SECONDARY));
+        assert!(!filter.passes(0)); // required bit missing
+        assert!(!filter.passes(FLAG_SECONDARY | FLAG_SUPPLEMENTARY)); // the rest of the default mask still applies
+    }
+
+    #[test]
+    fn required_flag_wins_over_explicit_exclude() {
+        let filter = flag_filter(&["--exclude-flags", "0x400", "--require-flags", "0x400"]);
+        assert!(filter.passes(FLAG_DUPLICATE));
+        assert!(!filter.passes(0));
+        assert!(filter.passes(FLAG_DUPLICATE | FLAG_SECONDARY)); // explicit mask replaces the default one
+    }
+
+    #[test]
+    fn count_supplementary_removes_it_from_exclude() {
+        assert!(flag_filter(&["--count-supplementary"]).passes(FLAG_SUPPLEMENTARY));
+        assert!(flag_filter(&["--exclude-flags", "0x800", "--count-supplementary"]).passes(FLAG_SUPPLEMENTARY));
+        assert!(!flag_filter(&["--count-supplementary"]).passes(FLAG_SECONDARY));
+    }
+
+    #[test]
+    fn saturated_regions_are_found_before_output() {
+        let saturated = RegionWithName::new(String::from("B"), Strand::Unknown, 0);
+        *saturated.count.borrow_mut() = add_coverage(i64::MAX - 1, 5, 1);
+        let tree: COITree<RegionWithName, u32> = COITree::new(&[Interval::new(100, 200, RegionWithName::new(String::from("A"), Strand::Unknown, 0)), Interval::new(300, 400, saturated)]);
+        let mut querents = FnvIndexMap::default();
+        querents.insert(String::from("chr1"), COITreeSortedQuerent::new(&tree));
+        assert_eq!(saturated_regions(&mut querents), ["B chr1:300-400"]);
+    }
 }
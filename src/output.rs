@@ -0,0 +1,430 @@
+use std::io::Write;
+
+use clap::ValueEnum;
+
+
+// Version of the report schema, bump it whenever columns or json keys are added, removed or change meaning
+pub const FORMAT_VERSION: u32 = 6;
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    Tsv,
+    Json,
+}
+
+
+// Single cell of the report, typed so every format can render it natively
+#[derive(PartialEq)]
+pub enum Value {
+    Text(String),
+    Int(i64),
+    Float(f64, usize), // value and number of decimals
+    Missing, // value can't be computed, N/A in tsv and null in json
+    Empty,   // column doesn't apply to this row
+}
+
+impl std::fmt::Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Value::Text(text) => write!(f, "{text}"),
+            Value::Int(value) => write!(f, "{value}"),
+            Value::Float(value, decimals) => write!(f, "{value:.decimals$}"),
+            Value::Missing => write!(f, "N/A"),
+            Value::Empty => Ok(()),
+        }
+    }
+}
+
+
+pub struct Table {
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<Value>>,
+}
+
+impl Table {
+    pub fn write_tsv<W: Write>(&self, writer: &mut W, comments: &str, threads: usize) -> std::io::Result<()> {
+        write!(writer, "{comments}")?;
+        writeln!(writer, "{}", self.columns.join("\t"))?;
+        self.write_tsv_rows(writer, threads)
+    }
+
+    // With several threads rows of consecutive chromosomes are formatted in parallel, a block per thread,
+    // and written in table order, so at most one block per thread is buffered
+    pub fn write_tsv_rows<W: Write>(&self, writer: &mut W, threads: usize) -> std::io::Result<()> {
+        let chrom = self.columns.iter().position(|column| column == "Chr");
+        if threads <= 1 || chrom.is_none() {
+            return write_tsv_block(writer, &self.rows);
+        }
+        let blocks: Vec<&[Vec<Value>]> = self.rows.chunk_by(|a, b| a.get(chrom.unwrap()) == b.get(chrom.unwrap())).collect();
+        for batch in blocks.chunks(threads) {
+            let formatted: Vec<Vec<u8>> = std::thread::scope(|scope| {
+                let handles: Vec<_> = batch.iter().map(|&rows| scope.spawn(move || {
+                    let mut text = Vec::new();
+                    write_tsv_block(&mut text, rows).map(|_| text)
+                })).collect();
+                handles.into_iter().map(|handle| handle.join().expect("Error formatting report rows")).collect::<std::io::Result<_>>()
+            })?;
+            for text in formatted {
+                writer.write_all(&text)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Rows are written as objects keyed by column name, columns that don't apply to a row are left out
+    pub fn write_json<W: Write>(&self, writer: &mut W, sample_name: &str, coord_base: i64, qc: &[(&str, Value)]) -> std::io::Result<()> {
+        writeln!(writer, "{{")?;
+        writeln!(writer, "  \"format_version\": {FORMAT_VERSION},")?;
+        writeln!(writer, "  \"coord_base\": {coord_base},")?;
+        writeln!(writer, "  \"sample\": {},", json_string(sample_name))?;
+        let qc: Vec<String> = qc.iter().map(|(name, value)| format!("{}: {}", json_string(name), json_value(value))).collect();
+        writeln!(writer, "  \"qc\": {{{}}},", qc.join(", "))?;
+        writeln!(writer, "  \"regions\": [")?;
+        for (i, row) in self.rows.iter().enumerate() {
+            let fields: Vec<String> = self.columns.iter().zip(row)
+                .filter(|(_, value)| !matches!(value, Value::Empty))
+                .map(|(column, value)| format!("{}: {}", json_string(column), json_value(value)))
+                .collect();
+            let separator = if i + 1 < self.rows.len() { "," } else { "" };
+            writeln!(writer, "    {{{}}}{separator}", fields.join(", "))?;
+        }
+        writeln!(writer, "  ]")?;
+        writeln!(writer, "}}")?;
+        Ok(())
+    }
+}
+
+
+fn write_tsv_block<W: Write>(writer: &mut W, rows: &[Vec<Value>]) -> std::io::Result<()> {
+    for row in rows {
+        let mut first = true;
+        for value in row {
+            if !first {
+                write!(writer, "\t")?;
+            }
+            write!(writer, "{value}")?;
+            first = false;
+        }
+        writeln!(writer)?;
+    }
+    Ok(())
+}
+
+
+pub fn json_string(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len() + 2);
+    escaped.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\t' => escaped.push_str("\\t"),
+            '\r' => escaped.push_str("\\r"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+fn json_value(value: &Value) -> String {
+    match value {
+        Value::Text(text) => json_string(text),
+        Value::Float(number, _) if !number.is_finite() => String::from("null"),
+        Value::Missing | Value::Empty => String::from("null"),
+        _ => value.to_string(),
+    }
+}
+
+
+// Amplicon rows of a report written by seq2c-rs as (Gene, Chr, Start, End) and MeanDepth
+struct SampleReport {
+    sample: String,
+    amplicons: Vec<([String; 4], String)>,
+}
+
+// Amplicon rows of a report as Sample and the SampleReport fields read line by line, reports grown with --append have several samples
+struct AmpliconRows {
+    path: String,
+    amplicon_tag: String,
+    comment_prefix: String,
+    lines: std::io::Lines<std::io::BufReader<std::fs::File>>,
+    width: usize,
+    // Gene, Chr, Start, End, Sample, Tag and MeanDepth
+    columns: [usize; 7],
+    // From the `coordinate base` comment of --report-format-version, reports without it have 0-based starts
+    coord_base: i64,
+    rows: usize,
+    found: bool,
+    done: bool,
+}
+
+impl AmpliconRows {
+    fn open(path: &str, amplicon_tag: &str, comment_prefix: &str) -> Result<AmpliconRows, String> {
+        let file = std::fs::File::open(path).map_err(|e| format!("can't read {path}: {e}"))?;
+        let mut rows = AmpliconRows{ path: path.to_string(), amplicon_tag: amplicon_tag.to_string(), comment_prefix: comment_prefix.to_string(),
+            lines: std::io::BufRead::lines(std::io::BufReader::new(file)), width: 0, columns: [0; 7], coord_base: 0, rows: 0, found: false, done: false };
+        let header = rows.next_line().ok_or(format!("{path} is empty"))??;
+        let header: Vec<&str> = header.split('\t').collect();
+        let column = |name: &str| header.iter().position(|column| *column == name).ok_or(format!("{path} has no {name} column"));
+        rows.columns = [column("Gene")?, column("Chr")?, column("Start")?, column("End")?, column("Sample")?, column("Tag")?, column("MeanDepth")?];
+        rows.width = header.len();
+        Ok(rows)
+    }
+
+    // Next line that isn't a comment
+    fn next_line(&mut self) -> Option<Result<String, String>> {
+        for line in self.lines.by_ref() {
+            match line {
+                Ok(line) if !self.comment_prefix.is_empty() && line.starts_with(&self.comment_prefix) => {
+                    if let Some(base) = line[self.comment_prefix.len()..].strip_prefix("coordinate base: ") {
+                        match base.parse() {
+                            Ok(base @ (0 | 1)) => self.coord_base = base,
+                            _ => return Some(Err(format!("{} has invalid coordinate base '{base}'", self.path))),
+                        }
+                    }
+                },
+                Ok(line) => return Some(Ok(line)),
+                Err(e) => return Some(Err(format!("can't read {}: {e}", self.path))),
+            }
+        }
+        None
+    }
+}
+
+impl Iterator for AmpliconRows {
+    type Item = Result<(String, [String; 4], String), String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.done {
+            let line = match self.next_line() {
+                Some(Ok(line)) => line,
+                Some(Err(e)) => {
+                    self.done = true;
+                    return Some(Err(e));
+                },
+                None => {
+                    self.done = true;
+                    return (!self.found).then(|| Err(format!("{} has no {} rows", self.path, self.amplicon_tag)));
+                },
+            };
+            self.rows += 1;
+            let fields: Vec<&str> = line.split('\t').collect();
+            if fields.len() != self.width {
+                self.done = true;
+                return Some(Err(format!("{} row {} has {} columns, header has {}", self.path, self.rows, fields.len(), self.width)));
+            }
+            let [gene, chrom, start, end, sample, tag, depth] = self.columns.map(|column| fields[column]);
+            if tag == self.amplicon_tag {
+                self.found = true;
+                return Some(Ok((sample.to_string(), [gene, chrom, start, end].map(String::from), depth.to_string())));
+            }
+        }
+        None
+    }
+}
+
+// Every sample of a report in order of first appearance
+fn read_reports(path: &str, amplicon_tag: &str, comment_prefix: &str) -> Result<Vec<SampleReport>, String> {
+    let mut reports: Vec<SampleReport> = Vec::new();
+    for row in AmpliconRows::open(path, amplicon_tag, comment_prefix)? {
+        let (sample, region, depth) = row?;
+        // Rows of appended reports come in blocks by sample, so the last sample is checked first
+        let index = match reports.iter().rposition(|report| report.sample == sample) {
+            Some(index) => index,
+            None => {
+                reports.push(SampleReport{ sample, amplicons: Vec::new() });
+                reports.len() - 1
+            },
+        };
+        reports[index].amplicons.push((region, depth));
+    }
+    Ok(reports)
+}
+
+
+// Per-sample summary of amplicon rows: MeanDepth is weighted by bed length (End - Start, +1 for reports with 1-based starts),
+// MedianDepth is over amplicons
+pub fn report_stats(paths: &[String], amplicon_tag: &str, comment_prefix: &str) -> Result<Table, String> {
+    // Sample name, genes, bed lengths and depths in order of first appearance
+    type SampleAmplicons = (String, std::collections::HashSet<String>, Vec<(i64, f64)>);
+    let mut samples: Vec<SampleAmplicons> = Vec::new();
+    for path in paths {
+        let rows = AmpliconRows::open(path, amplicon_tag, comment_prefix)?;
+        let coord_base = rows.coord_base;
+        for row in rows {
+            let (sample, [gene, chrom, start, end], depth) = row?;
+            let number = |value: &str| value.parse::<f64>().map_err(|_| format!("{path} has amplicon {chrom}:{start}-{end} with invalid value '{value}'"));
+            let (length, depth) = ((number(&end)? - number(&start)?) as i64 + coord_base, number(&depth)?);
+            let index = match samples.iter().position(|(name, _, _)| *name == sample) {
+                Some(index) => index,
+                None => {
+                    samples.push((sample, Default::default(), Vec::new()));
+                    samples.len() - 1
+                },
+            };
+            samples[index].1.insert(gene);
+            samples[index].2.push((length, depth));
+        }
+    }
+    let columns = ["Sample", "Genes", "Amplicons", "MeanDepth", "MedianDepth", "ZeroDepthAmplicons"].map(String::from).to_vec();
+    let rows = samples.into_iter().map(|(sample, genes, mut amplicons)| {
+        let length: i64 = amplicons.iter().map(|(length, _)| length).sum();
+        let mean = if length > 0 { Value::Float(amplicons.iter().map(|&(length, depth)| length as f64 * depth).sum::<f64>() / length as f64, 2) } else { Value::Missing };
+        amplicons.sort_by(|a, b| a.1.total_cmp(&b.1));
+        let middle = amplicons.len() / 2;
+        let median = if amplicons.len() % 2 == 1 { amplicons[middle].1 } else { (amplicons[middle - 1].1 + amplicons[middle].1) / 2.0 };
+        let zero = amplicons.iter().filter(|(_, depth)| *depth == 0.0).count();
+        vec![Value::Text(sample), Value::Int(genes.len() as i64), Value::Int(amplicons.len() as i64), mean, Value::Float(median, 2), Value::Int(zero as i64)]
+    }).collect();
+    Ok(Table{ columns, rows })
+}
+
+// Appends rows to a tsv report of another sample, the file is locked so concurrent runs don't interleave rows.
+// A missing or empty file gets the comments and header first, an existing one has to have the same columns
+pub fn append_tsv(path: &str, table: &Table, comments: &str, comment_prefix: &str, threads: usize) -> Result<(), String> {
+    let io_error = |e: std::io::Error| format!("can't append to {path}: {e}");
+    let mut file = std::fs::OpenOptions::new().read(true).append(true).create(true).open(path).map_err(io_error)?;
+    file.lock().map_err(io_error)?;
+    let mut text = String::new();
+    std::io::Read::read_to_string(&mut file, &mut text).map_err(io_error)?;
+    let mut writer = std::io::BufWriter::new(&file);
+    match text.lines().find(|line| comment_prefix.is_empty() || !line.starts_with(comment_prefix)) {
+        None => table.write_tsv(&mut writer, comments, threads).map_err(io_error)?,
+        Some(header) if header == table.columns.join("\t") => {
+            if !text.ends_with('\n') {
+                writeln!(writer).map_err(io_error)?;
+            }
+            table.write_tsv_rows(&mut writer, threads).map_err(io_error)?
+        },
+        Some(header) => return Err(format!("{path} has columns '{}', this run writes '{}', they have to be the same to append", header.replace('\t', ","), table.columns.join(","))),
+    }
+    writer.flush().map_err(io_error)
+}
+
+
+// Amplicon MeanDepth of a report by (Chr, Start, End), baseline of another run over the same panel
+pub fn read_baseline_depths(path: &str, amplicon_tag: &str, comment_prefix: &str) -> Result<std::collections::HashMap<(String, i64, i64), f64>, String> {
+    let mut reports = read_reports(path, amplicon_tag, comment_prefix)?;
+    if reports.len() > 1 {
+        return Err(format!("{path} has {} samples, a baseline report has to have one", reports.len()));
+    }
+    reports.remove(0).amplicons.into_iter().map(|([_, chrom, start, end], depth)| {
+        let number = |value: &str| value.parse().map_err(|_| format!("{path} has amplicon {chrom}:{start}-{end} with invalid value '{value}'"));
+        Ok(((chrom.clone(), number(&start)? as i64, number(&end)? as i64), number(&depth)?))
+    }).collect()
+}
+
+// Depth columns spilled to temporary files, removed when dropped so errors clean up too
+struct SpilledColumns(Vec<std::path::PathBuf>);
+
+impl Drop for SpilledColumns {
+    fn drop(&mut self) {
+        for path in &self.0 {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+// Rows of one sample in a report being merged, with tmpdir its depths go straight to a spilled column
+struct SampleBlock<'a> {
+    sample: String,
+    path: &'a str,
+    rows: usize,
+    spill: Option<std::io::BufWriter<std::fs::File>>,
+}
+
+impl SampleBlock<'_> {
+    // Samples after the first have to have as many amplicons as the first one
+    fn finish(self, first_sample: &str, first_path: &str, first_rows: usize) -> Result<(), String> {
+        if self.rows != first_rows {
+            return Err(format!("sample {} of {} has {} amplicons, {first_sample} of {first_path} has {first_rows}", self.sample, self.path, self.rows));
+        }
+        match self.spill {
+            Some(mut spill) => spill.flush().map_err(|e| e.to_string()),
+            None => Ok(()),
+        }
+    }
+}
+
+
+// Joins amplicon MeanDepth of several reports into one matrix with a column for each sample, all reports have to
+// share the same panel and sample names have to be unique. Reports grown with --append add a column for each of their samples.
+// Reports are streamed row by row, with tmpdir only the first sample is kept in memory and depths of the others are
+// written to spilled columns as they are read.
+pub fn merge_reports<W: Write>(writer: &mut W, paths: &[String], amplicon_tag: &str, comment_prefix: &str, tmpdir: Option<&str>) -> Result<(), String> {
+    let io_error = |e: std::io::Error| e.to_string();
+    // Amplicons of the first sample, rows of the others are checked against them
+    let mut first: Vec<([String; 4], String)> = Vec::new();
+    let mut first_path = "";
+    let mut samples: Vec<String> = Vec::new();
+    let mut columns: Vec<Vec<String>> = Vec::new();
+    let mut spilled = SpilledColumns(Vec::new());
+    let mut block: Option<SampleBlock> = None;
+    for path in paths {
+        for row in AmpliconRows::open(path, amplicon_tag, comment_prefix)? {
+            let (sample, region, depth) = row?;
+            // Rows of appended reports come in blocks by sample
+            if block.as_ref().is_none_or(|block| block.sample != sample || block.path != path) {
+                if let Some(block) = block.take() {
+                    block.finish(&samples[0], first_path, first.len())?;
+                }
+                if samples.contains(&sample) {
+                    return Err(format!("sample {sample} of {path} is already in an earlier report, sample names have to be unique"));
+                }
+                let spill = match tmpdir {
+                    _ if samples.is_empty() => {
+                        first_path = path;
+                        None
+                    },
+                    Some(tmpdir) => {
+                        let spill_path = std::path::Path::new(tmpdir).join(format!("seq2c-rs-{}-{}.column", std::process::id(), samples.len()));
+                        spilled.0.push(spill_path.clone());
+                        Some(std::io::BufWriter::new(std::fs::File::create(&spill_path).map_err(|e| format!("can't create {}: {e}", spill_path.display()))?))
+                    },
+                    None => {
+                        columns.push(Vec::new());
+                        None
+                    },
+                };
+                samples.push(sample.clone());
+                block = Some(SampleBlock{ sample, path, rows: 0, spill });
+            }
+            let block = block.as_mut().expect("block is started above");
+            if samples.len() == 1 {
+                first.push((region, depth));
+            } else {
+                if let Some((expected, _)) = first.get(block.rows).filter(|(expected, _)| *expected != region) {
+                    return Err(format!("{path} has amplicon {} where {first_path} has {}, reports are not from the same panel", region.join(":"), expected.join(":")));
+                }
+                match &mut block.spill {
+                    Some(spill) => writeln!(spill, "{depth}").map_err(io_error)?,
+                    None => columns.last_mut().expect("column is added with the block").push(depth),
+                }
+            }
+            block.rows += 1;
+        }
+    }
+    let block = block.ok_or("no reports to merge")?;
+    block.finish(&samples[0], first_path, first.len())?;
+
+    let mut spilled_readers = spilled.0.iter()
+        .map(|path| std::fs::File::open(path).map(|file| std::io::BufRead::lines(std::io::BufReader::new(file))))
+        .collect::<Result<Vec<_>, _>>().map_err(io_error)?;
+    writeln!(writer, "Gene\tChr\tStart\tEnd\t{}", samples.join("\t")).map_err(io_error)?;
+    for (i, (region, depth)) in first.iter().enumerate() {
+        write!(writer, "{}\t{depth}", region.join("\t")).map_err(io_error)?;
+        for column in &columns {
+            write!(writer, "\t{}", column[i]).map_err(io_error)?;
+        }
+        for lines in spilled_readers.iter_mut() {
+            let depth = lines.next().ok_or("spilled column ended early")?.map_err(io_error)?;
+            write!(writer, "\t{depth}").map_err(io_error)?;
+        }
+        writeln!(writer).map_err(io_error)?;
+    }
+    Ok(())
+}
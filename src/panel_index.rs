@@ -0,0 +1,110 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+
+use bio::bio_types::strand::Strand;
+
+
+// Parsed panel saved with --save-index, the format is private to seq2c-rs and only read back by the same version
+const MAGIC: &[u8; 8] = b"S2CIDX1\n";
+
+
+// Bed record after parsing and validation, the same data the interval trees are built from
+pub struct IndexedRegion {
+    pub chrom: usize, // index into chromosomes in bed order
+    pub first: i32,
+    pub last: i32,
+    pub name: String,
+    pub strand: Strand,
+    pub score_weight: f64,
+    pub n_bases: i64,
+    pub input_index: usize,
+}
+
+pub struct PanelIndex {
+    pub panel_hash: u64, // bed contents and options the regions depend on
+    pub chromosomes: Vec<String>,
+    pub regions: Vec<IndexedRegion>,
+}
+
+
+fn write_bytes<W: Write>(writer: &mut W, bytes: &[u8]) -> std::io::Result<()> {
+    writer.write_all(&(bytes.len() as u32).to_le_bytes())?;
+    writer.write_all(bytes)
+}
+
+pub fn save_index(path: &str, index: &PanelIndex) -> std::io::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    writer.write_all(MAGIC)?;
+    writer.write_all(&index.panel_hash.to_le_bytes())?;
+    writer.write_all(&(index.chromosomes.len() as u32).to_le_bytes())?;
+    for chrom in &index.chromosomes {
+        write_bytes(&mut writer, chrom.as_bytes())?;
+    }
+    writer.write_all(&(index.regions.len() as u64).to_le_bytes())?;
+    for region in &index.regions {
+        writer.write_all(&(region.chrom as u32).to_le_bytes())?;
+        writer.write_all(&region.first.to_le_bytes())?;
+        writer.write_all(&region.last.to_le_bytes())?;
+        let strand: u8 = match region.strand {
+            Strand::Forward => 1,
+            Strand::Reverse => 2,
+            Strand::Unknown => 0,
+        };
+        writer.write_all(&[strand])?;
+        writer.write_all(&region.score_weight.to_le_bytes())?;
+        writer.write_all(&region.n_bases.to_le_bytes())?;
+        writer.write_all(&(region.input_index as u64).to_le_bytes())?;
+        write_bytes(&mut writer, region.name.as_bytes())?;
+    }
+    writer.flush()
+}
+
+
+struct IndexReader(BufReader<File>);
+
+impl IndexReader {
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], String> {
+        let mut bytes = [0u8; N];
+        self.0.read_exact(&mut bytes).map_err(|_| String::from("index file is truncated"))?;
+        Ok(bytes)
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let length = u32::from_le_bytes(self.array()?) as usize;
+        let mut bytes = vec![0u8; length];
+        self.0.read_exact(&mut bytes).map_err(|_| String::from("index file is truncated"))?;
+        String::from_utf8(bytes).map_err(|_| String::from("index file has a name that is not utf-8"))
+    }
+}
+
+pub fn load_index(path: &str) -> Result<PanelIndex, String> {
+    let mut reader = IndexReader(BufReader::new(File::open(path).map_err(|e| format!("can't open index {path}: {e}"))?));
+    if reader.array::<8>().ok().as_ref() != Some(MAGIC) {
+        return Err(format!("{path} is not a seq2c-rs panel index"));
+    }
+    let panel_hash = u64::from_le_bytes(reader.array()?);
+    let chromosomes = (0..u32::from_le_bytes(reader.array()?))
+        .map(|_| reader.string())
+        .collect::<Result<Vec<_>, _>>()?;
+    let region_count = u64::from_le_bytes(reader.array()?);
+    let mut regions = Vec::new();
+    for _ in 0..region_count {
+        let chrom = u32::from_le_bytes(reader.array()?) as usize;
+        if chrom >= chromosomes.len() {
+            return Err(format!("{path} has a region of unknown chromosome {chrom}"));
+        }
+        let first = i32::from_le_bytes(reader.array()?);
+        let last = i32::from_le_bytes(reader.array()?);
+        let strand = match reader.array::<1>()?[0] {
+            1 => Strand::Forward,
+            2 => Strand::Reverse,
+            _ => Strand::Unknown,
+        };
+        let score_weight = f64::from_le_bytes(reader.array()?);
+        let n_bases = i64::from_le_bytes(reader.array()?);
+        let input_index = u64::from_le_bytes(reader.array()?) as usize;
+        let name = reader.string()?;
+        regions.push(IndexedRegion { chrom, first, last, name, strand, score_weight, n_bases, input_index });
+    }
+    Ok(PanelIndex { panel_hash, chromosomes, regions })
+}
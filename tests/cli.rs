@@ -0,0 +1,765 @@
+// End to end runs of the binary on small SAM, BED and VCF files written to a scratch directory
+
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+const SAM_HEADER: &str = "@HD\tVN:1.6\tSO:coordinate\n@SQ\tSN:chr1\tLN:10000\n@SQ\tSN:chr2\tLN:10000\n";
+
+// Empty directory for one test, left behind for inspection when the test fails
+fn scratch(test: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("seq2c-rs-test-{}-{test}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn write(dir: &Path, name: &str, text: &str) -> String {
+    let path = dir.join(name);
+    std::fs::write(&path, text).unwrap();
+    path.to_str().unwrap().to_string()
+}
+
+// SAM with the test header and one mapped forward read per (chrom, 1-based pos, cigar)
+fn sam(reads: &[(&str, i64, &str)]) -> String {
+    let mut text = String::from(SAM_HEADER);
+    for (i, (chrom, pos, cigar)) in reads.iter().enumerate() {
+        text += &format!("r{i}\t0\t{chrom}\t{pos}\t60\t{cigar}\t*\t0\t0\t*\t*\n");
+    }
+    text
+}
+
+// Single contig fasta with its faidx index, the sequence on one line
+fn fasta(dir: &Path, contig: &str, seq: &str) -> String {
+    write(dir, "ref.fa.fai", &format!("{contig}\t{}\t{}\t{}\t{}\n", seq.len(), contig.len() + 2, seq.len(), seq.len() + 1));
+    write(dir, "ref.fa", &format!(">{contig}\n{seq}\n"))
+}
+
+fn run(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_seq2c-rs")).args(args).output().unwrap()
+}
+
+// Report rows of a successful run split into fields, header included
+fn report(args: &[&str]) -> Vec<Vec<String>> {
+    let output = run(args);
+    assert!(output.status.success(), "run failed: {}", String::from_utf8_lossy(&output.stderr));
+    String::from_utf8(output.stdout).unwrap().lines()
+        .map(|line| line.split('\t').map(str::to_string).collect())
+        .collect()
+}
+
+
+#[test]
+fn vcf_depth_is_not_scaled_by_proportional_overlap() {
+    let dir = scratch("vcf_proportional");
+    let bam = write(&dir, "reads.sam", &sam(&[("chr1", 101, "50M"), ("chr1", 101, "50M")]));
+    let bed = write(&dir, "panel.bed", "chr1\t100\t200\tA\nchr1\t120\t220\tB\n");
+    let vcf = write(&dir, "sites.vcf", "##fileformat=VCFv4.2\n##contig=<ID=chr1,length=10000>\n#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\nchr1\t130\t.\tA\tG\t.\t.\t.\nchr1\t300\t.\tA\tG\t.\t.\t.\n");
+    for options in [&[][..], &["--proportional-overlap"][..]] {
+        let output = dir.join("depth.tsv");
+        let mut args = vec!["-b", &bam, "-N", "s", "-p", &bed, "--vcf", &vcf, "--vcf-output", output.to_str().unwrap(), "--force"];
+        args.extend(options);
+        report(&args);
+        assert_eq!(std::fs::read_to_string(&output).unwrap(), "CHROM\tPOS\tDEPTH\nchr1\t130\t2\nchr1\t300\t0\n", "with {options:?}");
+    }
+}
+
+
+// Covered bases of a 10M5D10M2N10M read at 101, as callable runs of depth 1 and amplicon MeanDepth
+fn del_skip_coverage(test: &str, del_covered: &str, skip_covered: &str) -> (String, String) {
+    let dir = scratch(test);
+    let bam = write(&dir, "reads.sam", &sam(&[("chr1", 101, "10M5D10M2N10M")]));
+    let bed = write(&dir, "panel.bed", "chr1\t100\t200\tA\n");
+    let callable = dir.join("callable.bed");
+    let rows = report(&["-b", &bam, "-N", "s", "-p", &bed, "--del-covered", del_covered, "--skip-covered", skip_covered,
+                        "--callable", callable.to_str().unwrap(), "--callable-depth", "1"]);
+    (std::fs::read_to_string(&callable).unwrap(), rows[1][7].clone())
+}
+
+#[test]
+fn deletions_and_skips_covered() {
+    let (runs, depth) = del_skip_coverage("del_skip_covered", "true", "true");
+    assert_eq!(runs, "chr1\t100\t137\tA\n");
+    assert_eq!(depth, "0.37");
+}
+
+#[test]
+fn deletions_covered_skips_not() {
+    let (runs, depth) = del_skip_coverage("del_covered", "true", "false");
+    assert_eq!(runs, "chr1\t100\t125\tA\nchr1\t127\t137\tA\n");
+    assert_eq!(depth, "0.35");
+}
+
+#[test]
+fn skips_covered_deletions_not() {
+    let (runs, depth) = del_skip_coverage("skip_covered", "false", "true");
+    assert_eq!(runs, "chr1\t100\t110\tA\nchr1\t115\t137\tA\n");
+    assert_eq!(depth, "0.32");
+}
+
+#[test]
+fn neither_deletions_nor_skips_covered() {
+    let (runs, depth) = del_skip_coverage("none_covered", "false", "false");
+    assert_eq!(runs, "chr1\t100\t110\tA\nchr1\t115\t125\tA\nchr1\t127\t137\tA\n");
+    assert_eq!(depth, "0.30");
+}
+
+
+#[test]
+fn merge_splits_appended_reports_by_sample() {
+    let dir = scratch("merge_appended");
+    let bam = write(&dir, "reads.sam", &sam(&[("chr1", 101, "50M")]));
+    let bed = write(&dir, "panel.bed", "chr1\t100\t200\tA\nchr2\t100\t200\tB\n");
+    let cohort = dir.join("cohort.tsv").to_str().unwrap().to_string();
+    for sample in ["s1", "s2"] {
+        report(&["-b", &bam, "-N", sample, "-p", &bed, "-o", &cohort, "--append"]);
+    }
+    let matrix = dir.join("matrix.tsv").to_str().unwrap().to_string();
+    let output = run(&["merge", "-o", &matrix, &cohort]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(std::fs::read_to_string(&matrix).unwrap(), "Gene\tChr\tStart\tEnd\ts1\ts2\nA\tchr1\t100\t200\t0.50\t0.50\nB\tchr2\t100\t200\t0.00\t0.00\n");
+
+    // The same samples twice
+    let output = run(&["merge", "-o", &matrix, "--force", &cohort, &cohort]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("sample s1"), "{}", String::from_utf8_lossy(&output.stderr));
+}
+
+
+#[test]
+fn merge_spills_each_sample_of_appended_report() {
+    let dir = scratch("merge_spilled");
+    let bam = write(&dir, "reads.sam", &sam(&[("chr1", 101, "50M"), ("chr2", 100, "101M")]));
+    let bed = write(&dir, "panel.bed", "chr1\t100\t200\tA\nchr2\t100\t200\tB\n");
+    let cohort = dir.join("cohort.tsv").to_str().unwrap().to_string();
+    for sample in ["s1", "s2", "s3"] {
+        report(&["-b", &bam, "-N", sample, "-p", &bed, "-o", &cohort, "--append"]);
+    }
+    let tmpdir = dir.join("tmp");
+    std::fs::create_dir(&tmpdir).unwrap();
+    let matrix = dir.join("matrix.tsv").to_str().unwrap().to_string();
+    let output = run(&["merge", "-o", &matrix, "--tmpdir", tmpdir.to_str().unwrap(), &cohort]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(std::fs::read_to_string(&matrix).unwrap(), "Gene\tChr\tStart\tEnd\ts1\ts2\ts3\nA\tchr1\t100\t200\t0.50\t0.50\t0.50\nB\tchr2\t100\t200\t1.00\t1.00\t1.00\n");
+    assert_eq!(std::fs::read_dir(&tmpdir).unwrap().count(), 0);
+
+    // A sample block cut short is reported and its spilled column removed
+    let text = std::fs::read_to_string(&cohort).unwrap();
+    let cut = write(&dir, "cut.tsv", &text.lines().filter(|line| !line.starts_with("s2\tB\t")).map(|line| format!("{line}\n")).collect::<String>());
+    let output = run(&["merge", "-o", &matrix, "--force", "--tmpdir", tmpdir.to_str().unwrap(), &cut]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("sample s2 of"), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(std::fs::read_dir(&tmpdir).unwrap().count(), 0);
+}
+
+
+#[test]
+fn stats_weight_by_length_in_the_report_coordinate_base() {
+    let dir = scratch("stats_coord_base");
+    let bam = write(&dir, "reads.sam", &sam(&[("chr1", 101, "100M"), ("chr1", 101, "100M"), ("chr1", 301, "10M")]));
+    let bed = write(&dir, "panel.bed", "chr1\t100\t200\tA\nchr1\t300\t310\tB\n");
+    let (zero, one) = (dir.join("zero.tsv").to_str().unwrap().to_string(), dir.join("one.tsv").to_str().unwrap().to_string());
+    report(&["-b", &bam, "-N", "s", "-p", &bed, "-o", &zero]);
+    report(&["-b", &bam, "-N", "s", "-p", &bed, "-o", &one, "--coord-base", "1", "--report-format-version"]);
+    for path in [&zero, &one] {
+        let output = run(&["stats", path]);
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        // (100 * 1.98 + 10 * 0.91) / 110 with the 0-based bed lengths of both reports
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "Sample\tGenes\tAmplicons\tMeanDepth\tMedianDepth\tZeroDepthAmplicons\ns\t2\t2\t1.88\t1.45\t0\n", "{path}");
+    }
+}
+
+
+#[test]
+fn dry_run_does_not_count_baseline_bam() {
+    let dir = scratch("dry_run_baseline");
+    let bam = write(&dir, "reads.sam", &sam(&[("chr1", 101, "50M")]));
+    let bed = write(&dir, "panel.bed", "chr1\t100\t200\tA\n");
+    // Counting it would fail on the broken record
+    let baseline = write(&dir, "baseline.sam", &format!("{SAM_HEADER}broken\n"));
+    let output = run(&["-b", &bam, "-N", "s", "-p", &bed, "--baseline", &baseline, "--dry-run"]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{stderr}");
+    assert!(!stderr.contains("Counting baseline"), "{stderr}");
+    let output = run(&["-b", &bam, "-N", "s", "-p", &bed, "--baseline", &baseline]);
+    assert!(!output.status.success());
+}
+
+
+#[test]
+fn dry_run_writes_no_files() {
+    let dir = scratch("dry_run_outputs");
+    let bam = write(&dir, "reads.sam", &sam(&[("chr1", 101, "50M")]));
+    let bed = write(&dir, "panel.bed", "chr1\t100\t200\tA\n");
+    let out = dir.join("out");
+    std::fs::create_dir(&out).unwrap();
+    let path = |name: &str| out.join(name).to_str().unwrap().to_string();
+    let output = run(&["-b", &bam, "-N", "s", "-p", &bed, "--dry-run", "-o", &path("report.tsv"), "--save-index", &path("panel.idx"),
+                       "--target-bed", &path("targets.bed"), "--amplicon-bed", &path("amplicons.bed"), "--matrix", &path("matrix.tsv"),
+                       "--manifest", &path("manifest.json"), "--callable", &path("callable.bed")]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(std::fs::read_dir(&out).unwrap().count(), 0);
+}
+
+
+fn truncated_bed_run(test: &str, strict: bool) -> Output {
+    let dir = scratch(test);
+    let bam = write(&dir, "reads.sam", &sam(&[("chr1", 101, "50M")]));
+    let bed = write(&dir, "panel.bed", "chr1\t100\t200\tA\nchr1\t300\nchr1\t500\t600\tB\n");
+    let mut args = vec!["-b", bam.as_str(), "-N", "s", "-p", bed.as_str()];
+    if strict {
+        args.push("--strict");
+    }
+    run(&args)
+}
+
+#[test]
+fn truncated_bed_line_is_skipped() {
+    let output = truncated_bed_run("truncated_bed", false);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{stderr}");
+    assert!(stderr.contains("Warning: Malformed BED record in") && stderr.contains("at line 2 is skipped"), "{stderr}");
+    let genes: Vec<String> = String::from_utf8(output.stdout).unwrap().lines().skip(1).map(|line| line.split('\t').nth(1).unwrap().to_string()).collect();
+    assert_eq!(genes, ["A", "A", "B", "B"]);
+}
+
+#[test]
+fn truncated_bed_line_is_rejected_with_strict() {
+    let output = truncated_bed_run("truncated_bed_strict", true);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(1), "{stderr}");
+    assert!(stderr.contains("Error: Malformed BED record in") && stderr.contains("at line 2 is rejected"), "{stderr}");
+    assert!(!stderr.contains("skipped"), "{stderr}");
+    assert!(output.stdout.is_empty());
+}
+
+
+// Rows follow gene and position whichever amplicons have the most reads
+#[test]
+fn row_order_does_not_depend_on_depth() {
+    let dir = scratch("row_order");
+    let bed = write(&dir, "panel.bed", "chr1\t300\t400\tA\nchr1\t100\t200\tA\nchr1\t500\t600\tA\nchr1\t100\t200\tB\nchr2\t100\t200\tC\n");
+    let shallow = write(&dir, "shallow.sam", &sam(&[("chr1", 101, "50M"), ("chr1", 501, "50M")]));
+    let deep = write(&dir, "deep.sam", &sam(&[("chr1", 501, "50M"), ("chr1", 501, "50M"), ("chr1", 501, "50M"), ("chr2", 101, "50M")]));
+    let order = |bam: &str| -> Vec<Vec<String>> {
+        report(&["-b", bam, "-N", "s", "-p", &bed]).into_iter().map(|row| row[1..6].to_vec()).collect()
+    };
+    let rows = order(&shallow);
+    assert_eq!(rows, order(&deep));
+    let starts: Vec<(&str, &str, &str)> = rows[1..].iter().map(|row| (row[0].as_str(), row[2].as_str(), row[4].as_str())).collect();
+    assert_eq!(starts, [("A", "100", "Amplicon"), ("A", "300", "Amplicon"), ("A", "500", "Amplicon"), ("A", "100", "Whole-Gene"),
+                        ("B", "100", "Amplicon"), ("B", "100", "Whole-Gene"), ("C", "100", "Amplicon"), ("C", "100", "Whole-Gene")]);
+}
+
+
+// A start == end record next to a regular one, under a read covering 101..200
+fn empty_record_run(test: &str, options: &[&str]) -> Output {
+    let dir = scratch(test);
+    let bam = write(&dir, "reads.sam", &sam(&[("chr1", 101, "100M")]));
+    let bed = write(&dir, "panel.bed", "chr1\t150\t150\tA\nchr1\t300\t400\tB\n");
+    let mut args = vec!["-b", bam.as_str(), "-N", "s", "-p", bed.as_str(), "--both-lengths"];
+    args.extend(options);
+    run(&args)
+}
+
+#[test]
+fn empty_record_is_skipped() {
+    let output = empty_record_run("empty_record", &[]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{stderr}");
+    assert!(stderr.contains("BED record chr1:150-150 in") && stderr.contains("is empty, it is skipped"), "{stderr}");
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "Sample\tGene\tChr\tStart\tEnd\tTag\tLength\tMeanDepth\tLengthRaw\tLengthPerl\n\
+                                                           s\tB\tchr1\t300\t400\tAmplicon\t101\t0.00\t100\t101\n\
+                                                           s\tB\tchr1\t300\t400\tWhole-Gene\t101\t0.00\t100\t101\n");
+}
+
+#[test]
+fn empty_record_as_single_base() {
+    let output = empty_record_run("empty_record_single_base", &["--empty-as-single-base"]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{stderr}");
+    assert!(!stderr.contains("is empty"), "{stderr}");
+    let rows: Vec<String> = String::from_utf8(output.stdout).unwrap().lines().skip(1).take(2).map(str::to_string).collect();
+    assert_eq!(rows, ["s\tA\tchr1\t150\t151\tAmplicon\t2\t1.00\t1\t2", "s\tA\tchr1\t150\t151\tWhole-Gene\t2\t1.00\t1\t2"]);
+}
+
+
+#[test]
+fn existing_amplicon_bed_index_needs_force() {
+    let dir = scratch("amplicon_bed_index");
+    let bam = write(&dir, "reads.sam", &sam(&[("chr1", 101, "50M")]));
+    let bed = write(&dir, "panel.bed", "chr1\t100\t200\tA\n");
+    let amplicons = dir.join("amplicons.bed.gz").to_str().unwrap().to_string();
+    let index = write(&dir, "amplicons.bed.gz.tbi", "kept");
+    let output = run(&["-b", &bam, "-N", "s", "-p", &bed, "--amplicon-bed", &amplicons]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success());
+    assert!(stderr.contains(&format!("output file {index} already exists")), "{stderr}");
+    assert!(!Path::new(&amplicons).exists());
+    assert_eq!(std::fs::read_to_string(&index).unwrap(), "kept");
+
+    report(&["-b", &bam, "-N", "s", "-p", &bed, "--amplicon-bed", &amplicons, "--force"]);
+    assert!(Path::new(&amplicons).exists());
+    assert_ne!(std::fs::read(&index).unwrap(), b"kept");
+}
+
+
+// Sorted by aligned start, the soft clip of the second read reaches back before the first one
+const CLIPPED_READS: [(&str, i64, &str); 2] = [("chr1", 101, "50M"), ("chr1", 102, "10S40M")];
+
+#[test]
+fn soft_clipped_sorted_bam_is_sorted_for_dedup() {
+    let dir = scratch("softclip_dedup");
+    let bam = write(&dir, "reads.sam", &sam(&CLIPPED_READS));
+    let bed = write(&dir, "panel.bed", "chr1\t80\t200\tA\n");
+    let output = run(&["-b", &bam, "-N", "s", "-p", &bed, "--include-softclip-span", "--dedup-by-position", "--strict"]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{stderr}");
+    assert!(!stderr.contains("not coordinate sorted"), "{stderr}");
+}
+
+#[test]
+fn soft_clipped_sorted_bam_counts_unique_starts() {
+    let dir = scratch("softclip_unique_start");
+    let bam = write(&dir, "reads.sam", &sam(&CLIPPED_READS));
+    let bed = write(&dir, "panel.bed", "chr1\t80\t200\tA\n");
+    let rows = report(&["-b", &bam, "-N", "s", "-p", &bed, "--include-softclip-span", "--count-mode", "unique-start", "--strict"]);
+    let column = rows[0].iter().position(|column| column == "UniqueStarts").unwrap();
+    assert_eq!(rows[1][5], "Amplicon");
+    assert_eq!(rows[1][column], "2");
+}
+
+#[test]
+fn soft_clipped_reads_join_coverage_islands() {
+    let dir = scratch("softclip_islands");
+    let bam = write(&dir, "reads.sam", &sam(&[("chr1", 101, "50M"), ("chr1", 160, "20S30M")]));
+    let rows = report(&["-b", &bam, "-N", "s", "--regions-from-bam", "--island-depth", "1", "--include-softclip-span"]);
+    let spans: Vec<&[String]> = rows[1..].iter().map(|row| &row[3..6]).collect();
+    assert_eq!(spans, [["100", "189", "Amplicon"], ["100", "189", "Whole-Gene"]]);
+}
+
+
+// Run of a sample with itself as --baseline, as the bam or as its report written with the same options,
+// over overlapping amplicons with primers at their outer ends
+fn self_baseline_run(test: &str, options: &[&str], primers: bool, report_baseline: bool) -> Output {
+    let dir = scratch(test);
+    let bam = write(&dir, "reads.sam", &sam(&[("chr1", 101, "100M"), ("chr1", 131, "60M"), ("chr1", 181, "10M5D40M")]));
+    let bed = write(&dir, "panel.bed", "chr1\t100\t200\tA\nchr1\t150\t250\tA\n");
+    let primer_bed = write(&dir, "primers.bed", "chr1\t100\t120\nchr1\t230\t250\n");
+    let mut args = vec!["-b", bam.as_str(), "-N", "s", "-p", bed.as_str()];
+    args.extend(options);
+    if primers {
+        args.extend(["--primers", primer_bed.as_str()]);
+    }
+    let baseline = dir.join("baseline.tsv").to_str().unwrap().to_string();
+    if report_baseline {
+        report(&[&args[..], &["-o", &baseline]].concat());
+    }
+    run(&[&args[..], &["--baseline", if report_baseline { &baseline } else { &bam }]].concat())
+}
+
+fn baseline_ratios(output: Output) -> Vec<String> {
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    String::from_utf8(output.stdout).unwrap().lines()
+        .filter(|line| line.contains("\tAmplicon\t"))
+        .map(|line| line.rsplit('\t').next().unwrap().to_string())
+        .collect()
+}
+
+#[test]
+fn self_baseline_bam_ratio_is_one() {
+    let output = self_baseline_run("self_baseline_bam", &["--del-covered", "false", "--min-mapq", "10"], false, false);
+    assert_eq!(baseline_ratios(output), ["1.00", "1.00"]);
+}
+
+#[test]
+fn self_baseline_report_ratio_is_one_with_read_options() {
+    let output = self_baseline_run("self_baseline_report", &["--proportional-overlap", "--del-gap", "2"], true, true);
+    assert_eq!(baseline_ratios(output), ["1.00", "1.00"]);
+}
+
+#[test]
+fn baseline_bam_is_rejected_with_read_options_it_is_not_counted_with() {
+    for (options, primers, option) in [(&["--proportional-overlap"][..], false, "--proportional-overlap"), (&[][..], true, "--primers")] {
+        let output = self_baseline_run("baseline_bam_options", options, primers, false);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert_eq!(output.status.code(), Some(1), "{stderr}");
+        assert!(stderr.contains(&format!("not with {option}")), "{stderr}");
+    }
+}
+
+
+// Genes A (one covered amplicon), B (one empty amplicon) and C (two covered amplicons), all passing a zero depth gate of 3 genes
+fn min_genes_covered_run(test: &str, option: &str) -> Output {
+    let dir = scratch(test);
+    let bam = write(&dir, "reads.sam", &sam(&[("chr1", 101, "100M"), ("chr1", 501, "100M"), ("chr1", 701, "100M")]));
+    let bed = write(&dir, "panel.bed", "chr1\t100\t200\tA\nchr1\t300\t400\tB\nchr1\t500\t600\tC\nchr1\t700\t800\tC\n");
+    run(&["-b", &bam, "-N", "s", "-p", &bed, "--gene-min-depth", "0", "--min-genes-covered", "3", option])
+}
+
+fn passing_whole_gene_rows(output: &Output) -> usize {
+    String::from_utf8_lossy(&output.stdout).lines().filter(|line| line.contains("\tWhole-Gene\t") && line.ends_with("\tPASS")).count()
+}
+
+#[test]
+fn min_genes_covered_skips_genes_suppressed_as_empty() {
+    let output = min_genes_covered_run("min_genes_suppressed", "--suppress-empty-genes");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(passing_whole_gene_rows(&output), 2);
+    assert!(stderr.contains("2 genes have mean depth of at least 0, 0 genes are below it"), "{stderr}");
+    assert_eq!(output.status.code(), Some(2), "{stderr}");
+}
+
+#[test]
+fn min_genes_covered_skips_genes_without_aggregate_row() {
+    let output = min_genes_covered_run("min_genes_single", "--no-amplicon-aggregate-when-single");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(passing_whole_gene_rows(&output), 1);
+    assert!(stderr.contains("1 genes have mean depth of at least 0, 0 genes are below it"), "{stderr}");
+    assert_eq!(output.status.code(), Some(2), "{stderr}");
+}
+
+
+#[test]
+fn mitochondrial_bed_contig_matches_bam_and_reference_spelling() {
+    let dir = scratch("mito_alias");
+    let bam = write(&dir, "reads.sam", "@HD\tVN:1.6\tSO:coordinate\n@SQ\tSN:MT\tLN:1000\nr0\t0\tMT\t101\t60\t50M\t*\t0\t0\t*\t*\n");
+    // 10 N bases at the start of the region
+    let reference = fasta(&dir, "MT", &format!("{}{}{}", "A".repeat(100), "N".repeat(10), "A".repeat(890)));
+    for chrom in ["M", "MT", "chrM", "chrMT"] {
+        let bed = write(&dir, "panel.bed", &format!("{chrom}\t100\t200\tA\n"));
+        let rows = report(&["-b", &bam, "-N", "s", "-p", &bed, "--reference", &reference, "--exclude-n", "--strict"]);
+        assert_eq!(rows[1], ["s", "A", chrom, "100", "200", "Amplicon", "91", "0.55"], "bed contig {chrom}");
+    }
+}
+
+
+#[test]
+fn vcf_sites_report_depth_once_per_position() {
+    let dir = scratch("vcf_sites");
+    // 101-150, 121-170 and 131-165 with a deletion of 141-145
+    let bam = write(&dir, "reads.sam", &sam(&[("chr1", 101, "50M"), ("chr1", 121, "50M"), ("chr1", 131, "10M5D20M")]));
+    let bed = write(&dir, "panel.bed", "chr1\t100\t200\tA\n");
+    let vcf = write(&dir, "sites.vcf", "##fileformat=VCFv4.2\n##contig=<ID=chr1,length=10000>\n##contig=<ID=chr2,length=10000>\n\
+                                        #CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\n\
+                                        chr1\t130\t.\tA\tG\t.\t.\t.\nchr1\t130\t.\tA\tT\t.\t.\t.\nchr1\t140\t.\tA\tG,T\t.\t.\t.\n\
+                                        chr1\t143\t.\tA\tG\t.\t.\t.\nchr1\t175\t.\tA\tG\t.\t.\t.\nchr2\t50\t.\tA\tG\t.\t.\t.\n");
+    let output = dir.join("depth.tsv");
+    report(&["-b", &bam, "-N", "s", "-p", &bed, "--vcf", &vcf, "--vcf-output", output.to_str().unwrap()]);
+    assert_eq!(std::fs::read_to_string(&output).unwrap(), "CHROM\tPOS\tDEPTH\nchr1\t130\t2\nchr1\t140\t3\nchr1\t143\t3\nchr1\t175\t0\nchr2\t50\t0\n");
+}
+
+
+#[test]
+fn callable_runs_and_bases_per_gene() {
+    let dir = scratch("callable");
+    // Depth 2 over 101-130, 3 over 131-150, 1 over 151-180 and 2 over 301-320
+    let bam = write(&dir, "reads.sam", &sam(&[("chr1", 101, "50M"), ("chr1", 101, "50M"), ("chr1", 131, "50M"), ("chr1", 301, "20M"), ("chr1", 301, "20M")]));
+    let bed = write(&dir, "panel.bed", "chr1\t100\t200\tA\nchr1\t300\t400\tA\n");
+    let callable = dir.join("callable.bed");
+    let rows = report(&["-b", &bam, "-N", "s", "-p", &bed, "--callable", callable.to_str().unwrap(), "--callable-depth", "2"]);
+    assert_eq!(std::fs::read_to_string(&callable).unwrap(), "chr1\t100\t150\tA\nchr1\t300\t320\tA\n");
+    assert_eq!(rows[0][8], "CallableBases");
+    let bases: Vec<(&str, &str)> = rows[1..].iter().map(|row| (row[5].as_str(), row[8].as_str())).collect();
+    assert_eq!(bases, [("Amplicon", "50"), ("Amplicon", "20"), ("Whole-Gene", "70")]);
+}
+
+
+#[test]
+fn strand_match_counts_reads_of_region_strand() {
+    let dir = scratch("strand_match");
+    let mut text = sam(&[("chr1", 101, "50M"), ("chr1", 101, "50M")]);
+    text += "rev\t16\tchr1\t101\t60\t50M\t*\t0\t0\t*\t*\n";
+    let bam = write(&dir, "reads.sam", &text);
+    let bed = write(&dir, "panel.bed", "chr1\t100\t200\tA\t0\t+\nchr1\t100\t200\tB\t0\t-\nchr1\t100\t200\tC\t0\t.\n");
+    let depths = |options: &[&str]| -> Vec<(String, String)> {
+        let mut args = vec!["-b", bam.as_str(), "-N", "s", "-p", bed.as_str()];
+        args.extend(options);
+        report(&args).into_iter().skip(1).filter(|row| row[5] == "Amplicon").map(|row| (row[1].clone(), row[7].clone())).collect()
+    };
+    let expected = |a: &str, b: &str, c: &str| vec![(String::from("A"), a.to_string()), (String::from("B"), b.to_string()), (String::from("C"), c.to_string())];
+    assert_eq!(depths(&[]), expected("1.49", "1.49", "1.49"));
+    assert_eq!(depths(&["--strand-match"]), expected("0.99", "0.50", "1.49"));
+}
+
+
+#[test]
+fn exclude_n_removes_reference_gaps_from_length() {
+    let dir = scratch("exclude_n");
+    let bam = write(&dir, "reads.sam", "@HD\tVN:1.6\tSO:coordinate\n@SQ\tSN:chr1\tLN:1000\nr0\t0\tchr1\t101\t60\t100M\t*\t0\t0\t*\t*\n");
+    // N bases at 140-160, 20 of them in the first amplicon and 10 in the second
+    let reference = fasta(&dir, "chr1", &format!("{}{}{}", "A".repeat(140), "N".repeat(20), "A".repeat(840)));
+    let bed = write(&dir, "panel.bed", "chr1\t100\t200\tA\nchr1\t150\t250\tA\nchr1\t300\t400\tB\n");
+    let lengths = |options: &[&str]| -> Vec<(String, String)> {
+        let mut args = vec!["-b", bam.as_str(), "-N", "s", "-p", bed.as_str(), "--reference", reference.as_str()];
+        args.extend(options);
+        report(&args).into_iter().skip(1).map(|row| (row[6].clone(), row[7].clone())).collect()
+    };
+    let expected = |rows: &[(&str, &str)]| -> Vec<(String, String)> { rows.iter().map(|&(length, depth)| (length.to_string(), depth.to_string())).collect() };
+    assert_eq!(lengths(&[]), expected(&[("101", "0.99"), ("101", "0.50"), ("202", "0.75"), ("101", "0.00"), ("101", "0.00")]));
+    assert_eq!(lengths(&["--exclude-n"]), expected(&[("81", "1.23"), ("91", "0.56"), ("172", "0.88"), ("101", "0.00"), ("101", "0.00")]));
+}
+
+
+#[test]
+fn flank_depth_is_counted_outside_of_amplicons() {
+    let dir = scratch("flank");
+    // 14 bases of the 80-99 left flank of A and 3 reads over half of its 201-220 right flank
+    let bam = write(&dir, "reads.sam", &sam(&[("chr1", 86, "20M"), ("chr1", 191, "20M"), ("chr1", 191, "20M"), ("chr1", 191, "20M")]));
+    let bed = write(&dir, "panel.bed", "chr1\t0\t50\tC\nchr1\t100\t200\tA\n");
+    let rows = report(&["-b", &bam, "-N", "s", "-p", &bed, "--flank", "20"]);
+    assert_eq!(rows[0][8..], ["LeftFlankDepth", "RightFlankDepth"]);
+    let depths: Vec<&[String]> = rows[1..].iter().map(|row| &row[5..]).collect();
+    // The left flank of C is before the contig start
+    assert_eq!(depths, [&["Amplicon", "101", "0.36", "0.70", "1.50"][..], &["Whole-Gene", "101", "0.36", "", ""],
+                        &["Amplicon", "51", "0.00", "N/A", "0.00"], &["Whole-Gene", "51", "0.00", "", ""]]);
+}
+
+
+#[test]
+fn split_by_rg_writes_a_column_per_read_group() {
+    let dir = scratch("split_by_rg");
+    // Reads without a group or with one missing from the header are only in the report
+    let bam = write(&dir, "reads.sam", "@HD\tVN:1.6\tSO:coordinate\n@SQ\tSN:chr1\tLN:10000\n@RG\tID:lib1\tSM:s\n@RG\tID:lib2\tSM:s\n\
+                                        r0\t0\tchr1\t101\t60\t50M\t*\t0\t0\t*\t*\tRG:Z:lib1\n\
+                                        r1\t0\tchr1\t101\t60\t50M\t*\t0\t0\t*\t*\tRG:Z:lib2\n\
+                                        r2\t0\tchr1\t121\t60\t50M\t*\t0\t0\t*\t*\tRG:Z:lib2\n\
+                                        r3\t0\tchr1\t301\t60\t50M\t*\t0\t0\t*\t*\n\
+                                        r4\t0\tchr1\t301\t60\t50M\t*\t0\t0\t*\t*\tRG:Z:lib3\n");
+    let bed = write(&dir, "panel.bed", "chr1\t100\t200\tA\nchr1\t300\t400\tB\n");
+    let matrix = dir.join("groups.tsv");
+    let output = run(&["-b", &bam, "-N", "s", "-p", &bed, "--split-by-rg", matrix.to_str().unwrap()]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{stderr}");
+    assert!(stderr.contains("2 reads have no read group from the bam header"), "{stderr}");
+    let depths: Vec<&str> = std::str::from_utf8(&output.stdout).unwrap().lines().skip(1).map(|line| line.rsplit('\t').next().unwrap()).collect();
+    assert_eq!(depths, ["1.49", "1.49", "0.99", "0.99"]);
+    assert_eq!(std::fs::read_to_string(&matrix).unwrap(), "Gene\tChr\tStart\tEnd\tlib1\tlib2\nA\tchr1\t100\t200\t0.50\t0.99\nB\tchr1\t300\t400\t0.00\t0.00\n");
+}
+
+
+#[test]
+fn collapse_amplicons_merges_rows_overlapping_enough() {
+    let dir = scratch("collapse_amplicons");
+    let bam = write(&dir, "reads.sam", &sam(&[("chr1", 101, "100M")]));
+    // The first two amplicons share the 51 bases of [150, 200]
+    let bed = write(&dir, "panel.bed", "chr1\t100\t200\tA\nchr1\t150\t250\tA\nchr1\t400\t500\tA\n");
+    let rows = |overlap: &str| -> Vec<Vec<String>> {
+        report(&["-b", &bam, "-N", "s", "-p", &bed, "--collapse-amplicons", overlap]).into_iter().skip(1).map(|row| row[3..].to_vec()).collect()
+    };
+    assert_eq!(rows("51"), [["100", "250", "Amplicon", "151", "0.66"], ["400", "500", "Amplicon", "101", "0.00"], ["100", "500", "Whole-Gene", "303", "0.50"]]);
+    assert_eq!(rows("52"), [["100", "200", "Amplicon", "101", "0.99"], ["150", "250", "Amplicon", "101", "0.50"], ["400", "500", "Amplicon", "101", "0.00"],
+                            ["100", "500", "Whole-Gene", "303", "0.50"]]);
+}
+
+
+#[test]
+fn primer_bases_are_not_counted() {
+    let dir = scratch("primers");
+    let bam = write(&dir, "reads.sam", &sam(&[("chr1", 101, "100M"), ("chr1", 301, "50M")]));
+    let bed = write(&dir, "panel.bed", "chr1\t100\t200\tA\nchr1\t300\t400\tB\n");
+    // 20 bases at each end of the read on A, none on B
+    let primers = write(&dir, "primers.bed", "chr1\t100\t120\nchr1\t180\t200\n");
+    let output = run(&["-b", &bam, "-N", "s", "-p", &bed, "--primers", &primers]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{stderr}");
+    assert!(stderr.contains("Reads on target: 2\n") && stderr.contains("Read bases clipped by primers: 40\n"), "{stderr}");
+    let depths: Vec<&str> = std::str::from_utf8(&output.stdout).unwrap().lines().skip(1).map(|line| line.rsplit('\t').next().unwrap()).collect();
+    assert_eq!(depths, ["0.59", "0.59", "0.50", "0.50"]);
+}
+
+
+#[test]
+fn append_adds_rows_under_one_header() {
+    let dir = scratch("append");
+    let bam = write(&dir, "reads.sam", &sam(&[("chr1", 101, "100M")]));
+    let bed = write(&dir, "panel.bed", "chr1\t100\t200\tA\n");
+    let cohort = dir.join("cohort.tsv").to_str().unwrap().to_string();
+    for sample in ["s1", "s2"] {
+        report(&["-b", &bam, "-N", sample, "-p", &bed, "-o", &cohort, "--append"]);
+    }
+    let expected = "Sample\tGene\tChr\tStart\tEnd\tTag\tLength\tMeanDepth\n\
+                    s1\tA\tchr1\t100\t200\tAmplicon\t101\t0.99\ns1\tA\tchr1\t100\t200\tWhole-Gene\t101\t0.99\n\
+                    s2\tA\tchr1\t100\t200\tAmplicon\t101\t0.99\ns2\tA\tchr1\t100\t200\tWhole-Gene\t101\t0.99\n";
+    assert_eq!(std::fs::read_to_string(&cohort).unwrap(), expected);
+
+    // Other columns than the existing header
+    let output = run(&["-b", &bam, "-N", "s3", "-p", &bed, "-o", &cohort, "--append", "--both-lengths"]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(1), "{stderr}");
+    assert!(stderr.contains("they have to be the same to append"), "{stderr}");
+    assert_eq!(std::fs::read_to_string(&cohort).unwrap(), expected);
+}
+
+
+#[test]
+fn start5_counts_reads_by_strand_aware_five_prime_end() {
+    let dir = scratch("start5");
+    // 5' ends at 110 (reverse, starting before A), 101, 200 (reverse) and 171 (before B, read overlaps it)
+    let bam = write(&dir, "reads.sam", "@HD\tVN:1.6\tSO:coordinate\n@SQ\tSN:chr1\tLN:10000\n\
+                                        r0\t16\tchr1\t91\t60\t20M\t*\t0\t0\t*\t*\nr1\t0\tchr1\t101\t60\t50M\t*\t0\t0\t*\t*\n\
+                                        r2\t16\tchr1\t151\t60\t50M\t*\t0\t0\t*\t*\nr3\t0\tchr1\t171\t60\t40M\t*\t0\t0\t*\t*\n");
+    let bed = write(&dir, "panel.bed", "chr1\t100\t150\tA\nchr1\t180\t250\tB\n");
+    let rows = report(&["-b", &bam, "-N", "s", "-p", &bed, "--count-mode", "start5"]);
+    assert_eq!(rows[0][8], "StartCount");
+    let counts: Vec<(&str, &str)> = rows[1..].iter().map(|row| (row[5].as_str(), row[8].as_str())).collect();
+    assert_eq!(counts, [("Amplicon", "2"), ("Whole-Gene", ""), ("Amplicon", "1"), ("Whole-Gene", "")]);
+}
+
+
+#[test]
+fn unique_starts_count_distinct_start_and_strand() {
+    let dir = scratch("unique_start");
+    let bam = write(&dir, "reads.sam", "@HD\tVN:1.6\tSO:coordinate\n@SQ\tSN:chr1\tLN:10000\n\
+                                        r0\t0\tchr1\t101\t60\t50M\t*\t0\t0\t*\t*\nr1\t0\tchr1\t101\t60\t50M\t*\t0\t0\t*\t*\n\
+                                        r2\t16\tchr1\t101\t60\t50M\t*\t0\t0\t*\t*\nr3\t0\tchr1\t121\t60\t50M\t*\t0\t0\t*\t*\n\
+                                        r4\t0\tchr1\t301\t60\t50M\t*\t0\t0\t*\t*\nr5\t0\tchr1\t301\t60\t50M\t*\t0\t0\t*\t*\n");
+    let bed = write(&dir, "panel.bed", "chr1\t100\t200\tA\nchr1\t300\t400\tB\n");
+    let rows = report(&["-b", &bam, "-N", "s", "-p", &bed, "--count-mode", "unique-start"]);
+    assert_eq!(rows[0][8], "UniqueStarts");
+    // Depth is counted from all reads
+    let counts: Vec<&[String]> = rows[1..].iter().map(|row| &row[5..]).collect();
+    assert_eq!(counts, [&["Amplicon", "101", "1.98", "3"][..], &["Whole-Gene", "101", "1.98", ""], &["Amplicon", "101", "0.99", "1"], &["Whole-Gene", "101", "0.99", ""]]);
+}
+
+
+#[test]
+fn refflat_exons_of_transcripts_are_merged_per_gene() {
+    let dir = scratch("refflat");
+    let bam = write(&dir, "reads.sam", &sam(&[("chr1", 101, "100M")]));
+    // Two GENE1 transcripts with overlapping and touching exons, a refGene line and a malformed one
+    let table = write(&dir, "genes.txt", "#geneName\tname\tchrom\tstrand\ttxStart\ttxEnd\tcdsStart\tcdsEnd\texonCount\texonStarts\texonEnds\n\
+                                          GENE1\tNM_1\tchr1\t+\t100\t400\t120\t380\t2\t100,300,\t200,400,\n\
+                                          GENE1\tNM_2\tchr1\t+\t150\t450\t150\t450\t2\t150,400,\t250,450,\n\
+                                          GENE2\tNM_3\tchr2\t-\t100\t200\t100\t200\t1\t100,\t200,\n\
+                                          585\tNM_4\tchr2\t+\t500\t600\t500\t600\t1\t500,\t600,\t0\tGENE3\tcmpl\tcmpl\t0,\n\
+                                          bad\tline\n");
+    let output = run(&["-b", &bam, "-N", "s", "--refflat", &table]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{stderr}");
+    assert!(stderr.contains("Malformed refFlat line 6"), "{stderr}");
+    let rows: Vec<&str> = std::str::from_utf8(&output.stdout).unwrap().lines().skip(1).collect();
+    assert_eq!(rows, ["s\tGENE1\tchr1\t100\t250\tAmplicon\t151\t0.66", "s\tGENE1\tchr1\t300\t450\tAmplicon\t151\t0.00", "s\tGENE1\tchr1\t100\t450\tWhole-Gene\t302\t0.33",
+                      "s\tGENE2\tchr2\t100\t200\tAmplicon\t101\t0.00", "s\tGENE2\tchr2\t100\t200\tWhole-Gene\t101\t0.00",
+                      "s\tGENE3\tchr2\t500\t600\tAmplicon\t101\t0.00", "s\tGENE3\tchr2\t500\t600\tWhole-Gene\t101\t0.00"]);
+}
+
+
+#[test]
+fn gzipped_amplicon_bed_is_sorted_and_tabix_indexed() {
+    use rust_htslib::tbx::{self, Read as _};
+    use std::io::Read as _;
+
+    let dir = scratch("amplicon_bed_gz");
+    let bam = write(&dir, "reads.sam", &sam(&[("chr1", 101, "100M"), ("chr1", 101, "100M")]));
+    let bed = write(&dir, "panel.bed", "chr1\t300\t400\tB\nchr1\t100\t200\tA\nchr2\t100\t200\tC\n");
+    let amplicons = dir.join("amplicons.bed.gz").to_str().unwrap().to_string();
+    report(&["-b", &bam, "-N", "s", "-p", &bed, "--amplicon-bed", &amplicons]);
+    let mut text = String::new();
+    rust_htslib::bgzf::Reader::from_path(&amplicons).unwrap().read_to_string(&mut text).unwrap();
+    assert_eq!(text, "chr1\t100\t200\tA\t2\nchr1\t300\t400\tB\t0\nchr2\t100\t200\tC\t0\n");
+
+    let mut reader = tbx::Reader::from_path(&amplicons).unwrap();
+    let tid = reader.tid("chr1").unwrap();
+    reader.fetch(tid, 250, 350).unwrap();
+    let records: Vec<String> = reader.records().map(|record| String::from_utf8(record.unwrap()).unwrap()).collect();
+    assert_eq!(records, ["chr1\t300\t400\tB\t0"]);
+}
+
+
+// Flanks of amplicons near both ends of a 10000 base contig with reads at the contig start and end
+fn contig_edge_flanks(test: &str, strict: bool) -> Output {
+    let dir = scratch(test);
+    let bam = write(&dir, "reads.sam", &sam(&[("chr1", 1, "10M"), ("chr1", 9981, "20M")]));
+    let bed = write(&dir, "panel.bed", "chr1\t5\t50\tA\nchr1\t9950\t9990\tB\n");
+    let mut args = vec!["-b", bam.as_str(), "-N", "s", "-p", bed.as_str(), "--flank", "20"];
+    if strict {
+        args.push("--strict");
+    }
+    run(&args)
+}
+
+#[test]
+fn flanks_are_clipped_to_the_contig() {
+    let output = contig_edge_flanks("flank_contig_edges", false);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let flanks: Vec<Vec<String>> = String::from_utf8(output.stdout).unwrap().lines()
+        .filter(|line| line.contains("\tAmplicon\t"))
+        .map(|line| line.split('\t').skip(8).map(str::to_string).collect())
+        .collect();
+    // [0, 4] before A with 4 covered bases, [9991, 10000] after B covered once
+    assert_eq!(flanks, [["0.80", "0.00"], ["0.00", "1.00"]]);
+}
+
+#[test]
+fn flanks_past_the_contig_fail_with_strict() {
+    let output = contig_edge_flanks("flank_contig_edges_strict", true);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(1), "{stderr}");
+    assert!(stderr.contains("Error: 2 flanks extend past the start or end of their contig"), "{stderr}");
+    assert!(output.stdout.is_empty());
+}
+
+
+// Bam with a 3 Gb contig, its positions don't fit the i32 coordinates of the region trees
+fn oversized_contig_run(test: &str, bed: &str) -> Output {
+    let dir = scratch(test);
+    let bam = write(&dir, "reads.sam", "@HD\tVN:1.6\tSO:coordinate\n@SQ\tSN:chr1\tLN:10000\n@SQ\tSN:big\tLN:3000000000\n\
+                                        r0\t0\tchr1\t101\t60\t100M\t*\t0\t0\t*\t*\n");
+    let bed = write(&dir, "panel.bed", bed);
+    run(&["-b", &bam, "-N", "s", "-p", &bed])
+}
+
+#[test]
+fn oversized_contig_without_targets_is_ignored() {
+    let output = oversized_contig_run("oversized_contig_untargeted", "chr1\t100\t200\tA\n");
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(String::from_utf8(output.stdout).unwrap().lines().nth(1), Some("s\tA\tchr1\t100\t200\tAmplicon\t101\t0.99"));
+}
+
+#[test]
+fn oversized_contig_with_targets_fails() {
+    let output = oversized_contig_run("oversized_contig_targeted", "chr1\t100\t200\tA\nbig\t100\t200\tB\n");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(1), "{stderr}");
+    assert!(stderr.contains("Error: bam contig big has 3000000000 bases, contigs with targets can't be longer than 2147483647"), "{stderr}");
+}
+
+#[test]
+fn bed_record_beyond_i32_positions_fails() {
+    let output = oversized_contig_run("oversized_bed_record", "big\t2500000000\t2500000100\tB\n");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(1), "{stderr}");
+    assert!(stderr.contains("ends beyond position 2147483647, contigs longer than that are not supported"), "{stderr}");
+}
+
+
+// Span and per-base coverage of a read with a 5 base deletion, as MeanDepth and callable runs
+fn del_gap_coverage(test: &str, del_gap: &str) -> (String, String) {
+    let dir = scratch(test);
+    let bam = write(&dir, "reads.sam", &sam(&[("chr1", 101, "10M5D10M")]));
+    let bed = write(&dir, "panel.bed", "chr1\t100\t200\tA\n");
+    let callable = dir.join("callable.bed");
+    let rows = report(&["-b", &bam, "-N", "s", "-p", &bed, "--del-gap", del_gap, "--callable", callable.to_str().unwrap(), "--callable-depth", "1"]);
+    (rows[1][7].clone(), std::fs::read_to_string(&callable).unwrap())
+}
+
+#[test]
+fn deletion_at_del_gap_stays_covered() {
+    assert_eq!(del_gap_coverage("del_gap_at_threshold", "5"), (String::from("0.25"), String::from("chr1\t100\t125\tA\n")));
+}
+
+#[test]
+fn deletion_longer_than_del_gap_is_a_gap() {
+    assert_eq!(del_gap_coverage("del_gap_below_threshold", "4"), (String::from("0.20"), String::from("chr1\t100\t110\tA\nchr1\t115\t125\tA\n")));
+}
+
+
+#[test]
+fn threshold_columns_are_named_by_depth() {
+    let dir = scratch("thresholds");
+    let bam = write(&dir, "reads.sam", &sam(&[("chr1", 101, "100M"), ("chr1", 101, "50M")]));
+    let bed = write(&dir, "panel.bed", "chr1\t100\t200\tA\n");
+    let rows = report(&["-b", &bam, "-N", "s", "-p", &bed, "--thresholds", "1,2,5"]);
+    assert_eq!(rows[0][8..], ["Fraction1x", "Fraction2x", "Fraction5x"]);
+    assert_eq!(rows[1][8..], ["1.0000", "0.5000", "0.0000"]);
+}
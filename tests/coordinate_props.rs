@@ -0,0 +1,59 @@
+use proptest::prelude::*;
+use rust_htslib::bam::record::Cigar;
+
+use seq2c_rs::{calculate_coverage, reference_span, SpanOps};
+
+
+// Well formed end-inclusive interval, end >= start
+fn interval() -> impl Strategy<Value = (i64, i64)> {
+    (0..10_000i64, 0..500i64).prop_map(|(start, length)| (start, start + length))
+}
+
+fn cigar_op() -> impl Strategy<Value = Cigar> {
+    (0..8, 1..50u32).prop_map(|(kind, length)| match kind {
+        0 => Cigar::Match(length),
+        1 => Cigar::Equal(length),
+        2 => Cigar::Diff(length),
+        3 => Cigar::Del(length),
+        4 => Cigar::RefSkip(length),
+        5 => Cigar::Ins(length),
+        6 => Cigar::SoftClip(length),
+        _ => Cigar::HardClip(length),
+    })
+}
+
+fn span_ops() -> impl Strategy<Value = SpanOps> {
+    proptest::sample::subsequence(vec!["M", "=", "X", "D", "N"], 1..=5).prop_map(|ops| ops.join(",").parse().unwrap())
+}
+
+
+proptest! {
+    #[test]
+    fn coverage_is_symmetric_and_never_negative(a in interval(), b in interval()) {
+        let overlap = calculate_coverage(a.0..a.1, b.0..b.1);
+        prop_assert_eq!(overlap, calculate_coverage(b.0..b.1, a.0..a.1));
+        prop_assert!(overlap >= 0);
+        prop_assert!(overlap <= (a.1 - a.0 + 1).min(b.1 - b.0 + 1));
+    }
+
+    #[test]
+    fn coverage_of_disjoint_intervals_is_zero(a in interval(), gap in 1..1_000i64, length in 0..500i64) {
+        let b_start = a.1 + gap;
+        prop_assert_eq!(calculate_coverage(a.0..a.1, b_start..b_start + length), 0);
+    }
+
+    #[test]
+    fn coverage_of_nested_interval_is_its_length(b in interval(), offset in 0..500i64, length in 0..500i64) {
+        let start = b.0 + offset.min(b.1 - b.0);
+        let end = (start + length).min(b.1);
+        prop_assert_eq!(calculate_coverage(start..end, b.0..b.1), end - start + 1);
+    }
+
+    #[test]
+    fn span_length_is_sum_of_span_operations(cigar in proptest::collection::vec(cigar_op(), 0..12), pos in 0..1_000_000i64, ops in span_ops()) {
+        let (start, end) = reference_span(&cigar, pos, ops);
+        let length: i64 = cigar.iter().filter(|op| ops.contains(op)).map(|op| op.len() as i64).sum();
+        prop_assert_eq!(start, pos + 1);
+        prop_assert_eq!(end - start + 1, length);
+    }
+}